@@ -0,0 +1,69 @@
+// cargo run --example debugger -- game.nes
+//
+// A command-line debugger for a ROM, see nes_emu::debugger for the commands.
+
+use nes_emu::bus::Bus;
+use nes_emu::cartridge::Rom;
+use nes_emu::cpu::CPU;
+use nes_emu::debugger::{Command, Debugger};
+use std::env;
+use std::io::{self, BufRead, Write};
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: debugger game.nes");
+            std::process::exit(2);
+        }
+    };
+    let bytes = std::fs::read(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1)
+    });
+    let rom = Rom::new(&bytes).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1)
+    });
+    let mut cpu = CPU::new(Bus::new(rom));
+    cpu.power_on();
+
+    let mut debugger = Debugger::new(cpu);
+    if let Err(e) = repl(&mut debugger) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+// Reads commands until q or the end of stdin. A bad command is reported and skipped, an empty
+// line is ignored without prompting again.
+fn repl(debugger: &mut Debugger) -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let (mut input, mut out) = (stdin.lock(), stdout.lock());
+    debugger.show_location(&mut out)?;
+    let mut line = String::new();
+    let mut prompt = true;
+    loop {
+        if prompt {
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        prompt = !line.trim().is_empty();
+        if !prompt {
+            continue;
+        }
+        match Command::parse(&line) {
+            Ok(command) => {
+                if !debugger.execute(command, &mut out)? {
+                    return Ok(());
+                }
+            }
+            Err(e) => writeln!(out, "{}", e)?,
+        }
+    }
+}
@@ -1,6 +1,12 @@
 use crate::cartridge::Rom;
-use crate::cpu::Mem;
-use crate::ppu::PPU;
+use crate::clock::{MasterClock, Region};
+use crate::cpu::{CpuBus, Mem};
+use crate::heatmap::Heatmap;
+use crate::mapper::{self, Mapper};
+use crate::ppu::{SpriteBox, PPU};
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
 
 //  _______________ $10000  _______________
 // | PRG-ROM       |       |               |
@@ -34,11 +40,88 @@ const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRROR_START: u16 = 0x2008;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const PRG_RAM: u16 = 0x6000;
 
+bitflags! {
+    /// Devices that can pull the CPU's /IRQ line low. The line is wired-OR: it stays
+    /// asserted while any source holds it, until that device is acknowledged.
+    pub struct IrqSource: u8 {
+        const APU_FRAME = 0b0000_0001;
+        const APU_DMC   = 0b0000_0010;
+        const MAPPER    = 0b0000_0100;
+    }
+}
+
+fn is_write_only(addr: u16) -> bool {
+    match addr {
+        PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
+            !matches!(addr & 0b00100000_00000111, 0x2002 | 0x2004 | 0x2007)
+        }
+        0x4014 => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    DummyRead,
+    DummyWrite,
+    DmaRead,
+    DmaWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub cpu_cycle: usize,
+    pub addr: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+}
+
+// Bounded log of CPU-visible bus activity; only accesses within `range` are kept
+#[derive(Clone)]
+struct AccessLog {
+    entries: VecDeque<BusAccess>,
+    capacity: usize,
+    range: RangeInclusive<u16>,
+    // one bus access per CPU cycle, so this approximates the cycle within the current instruction
+    accesses_since_tick: usize,
+}
+
+// Sees (addr, value) and returns what the reader gets instead
+pub type ReadHook = dyn FnMut(u16, u8) -> u8 + Send;
+// Sees (addr, old, new) and returns the value to store, or None to drop the write. `old` is
+// the byte currently there; registers, whose reads have side effects, report the open bus.
+pub type WriteHook = dyn FnMut(u16, u8, u8) -> Option<u8> + Send;
+
+// A cloned Bus shares its hooks with the original
+#[derive(Clone, Default)]
+struct MemHooks {
+    reads: Vec<(RangeInclusive<u16>, Arc<Mutex<ReadHook>>)>,
+    writes: Vec<(RangeInclusive<u16>, Arc<Mutex<WriteHook>>)>,
+}
+
+#[derive(Clone)]
 pub struct Bus {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
+    mapper: Box<dyn Mapper>,
+    open_bus: u8, // last value driven on the data bus, what unmapped reads see
+    oam_dma: bool, // a $4014 write is waiting for the CPU to stall
+    dmc_fetch: Option<u16>,
+    dmc_sample: Option<u8>,
+    last_access: (u16, bool), // address of the CPU's last access, and whether it wrote
     ppu: PPU,
+    ppu_writes: u64, // CPU writes to $2000-$3FFF and $4014
+    cycles: usize,
+    clock: MasterClock,
+    irq_sources: IrqSource,
+    irq_low_since: Option<usize>, // CPU cycle the IRQ line went low
+    nmi_edge_at: Option<usize>, // NMI raised by something other than the PPU, at a CPU cycle
+    access_log: Option<AccessLog>,
+    heatmap: Option<Box<Heatmap>>,
+    hooks: MemHooks,
 }
 
 impl Bus {
@@ -46,36 +129,268 @@ impl Bus {
         let ppu = PPU::new(rom.chr_rom, rom.screen_mirroring);
         Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            mapper: mapper::new(rom.mapper, rom.prg_rom, rom.prg_ram_size),
+            open_bus: 0,
+            oam_dma: false,
+            dmc_fetch: None,
+            dmc_sample: None,
+            last_access: (0, false),
             ppu: ppu,
+            ppu_writes: 0,
+            cycles: 0,
+            clock: MasterClock::new(Region::default()),
+            irq_sources: IrqSource::empty(),
+            irq_low_since: None,
+            nmi_edge_at: None,
+            access_log: None,
+            heatmap: None,
+            hooks: MemHooks::default(),
+        }
+    }
+
+    // takes effect from the next tick; cycles already counted stay as they were
+    pub fn set_region(&mut self, region: Region) {
+        self.clock = MasterClock::new(region);
+        self.ppu.set_region(region);
+    }
+
+    pub fn region(&self) -> Region {
+        self.clock.region()
+    }
+
+    pub fn enable_access_log(&mut self, capacity: usize, range: RangeInclusive<u16>) {
+        self.access_log = Some(AccessLog {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            range,
+            accesses_since_tick: 0,
+        });
+    }
+
+    pub fn disable_access_log(&mut self) {
+        self.access_log = None;
+    }
+
+    // drains everything logged so far, oldest first
+    pub fn take_access_log(&mut self) -> Vec<BusAccess> {
+        match self.access_log.as_mut() {
+            Some(log) => log.entries.drain(..).collect(),
+            None => vec![],
+        }
+    }
+
+    // Counts every access the access log would see, dummy and DMA ones included, from now on.
+    // Enabling it again keeps the counts.
+    pub fn enable_heatmap(&mut self) {
+        if self.heatmap.is_none() {
+            self.heatmap = Some(Box::new(Heatmap::new()));
+        }
+    }
+
+    pub fn disable_heatmap(&mut self) {
+        self.heatmap = None;
+    }
+
+    // zeroes the counts, e.g. at the start of a frame
+    pub fn reset_heatmap(&mut self) {
+        if let Some(heatmap) = self.heatmap.as_mut() {
+            heatmap.clear();
+        }
+    }
+
+    pub fn heatmap(&self) -> Option<&Heatmap> {
+        self.heatmap.as_deref()
+    }
+
+    #[inline]
+    fn log_access(&mut self, addr: u16, value: u8, kind: AccessKind) {
+        if let Some(heatmap) = self.heatmap.as_mut() {
+            match kind {
+                AccessKind::Read | AccessKind::DummyRead | AccessKind::DmaRead => {
+                    heatmap.record_read(addr)
+                }
+                _ => heatmap.record_write(addr),
+            }
+        }
+        if let Some(log) = self.access_log.as_mut() {
+            let cpu_cycle = self.cycles + log.accesses_since_tick;
+            log.accesses_since_tick += 1;
+            if !log.range.contains(&addr) || log.capacity == 0 {
+                return;
+            }
+            if log.entries.len() == log.capacity {
+                log.entries.pop_front();
+            }
+            log.entries.push_back(BusAccess {
+                cpu_cycle,
+                addr,
+                value,
+                kind,
+            });
+        }
+    }
+
+    // Hooks see every access that reaches the memory map: CPU reads and writes, dummy
+    // accesses, DMA and debugger pokes. Hooks on the same address run in the order added,
+    // each one seeing the value the previous one returned.
+    pub fn add_read_hook<F>(&mut self, range: RangeInclusive<u16>, hook: F)
+    where
+        F: FnMut(u16, u8) -> u8 + Send + 'static,
+    {
+        self.hooks.reads.push((range, Arc::new(Mutex::new(hook))));
+    }
+
+    pub fn add_write_hook<F>(&mut self, range: RangeInclusive<u16>, hook: F)
+    where
+        F: FnMut(u16, u8, u8) -> Option<u8> + Send + 'static,
+    {
+        self.hooks.writes.push((range, Arc::new(Mutex::new(hook))));
+    }
+
+    // A device answering at `addr` in place of memory, like the random byte at $FE and the last
+    // key at $FF of the tutorial snake game. Whatever RAM holds there is ignored.
+    pub fn map_read<F>(&mut self, addr: u16, mut provider: F)
+    where
+        F: FnMut() -> u8 + Send + 'static,
+    {
+        self.add_read_hook(addr..=addr, move |_, _| provider());
+    }
+
+    // writes to `addr` go to `consumer` and never reach memory
+    pub fn map_write<F>(&mut self, addr: u16, mut consumer: F)
+    where
+        F: FnMut(u8) + Send + 'static,
+    {
+        self.add_write_hook(addr..=addr, move |_, _, data| {
+            consumer(data);
+            None
+        });
+    }
+
+    // drops map_read/map_write providers as well
+    pub fn clear_hooks(&mut self) {
+        self.hooks = MemHooks::default();
+    }
+
+    fn hooked_read(&self, addr: u16, mut data: u8) -> u8 {
+        for (range, hook) in &self.hooks.reads {
+            if range.contains(&addr) {
+                data = (hook.lock().unwrap())(addr, data);
+            }
+        }
+        data
+    }
+
+    fn hooked_write(&mut self, addr: u16, mut data: u8) -> Option<u8> {
+        if !self.hooks.writes.iter().any(|(range, _)| range.contains(&addr)) {
+            return Some(data);
         }
+        let old = match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0x07ff) as usize],
+            PRG_RAM..=0xFFFF => self.mapper.peek_prg(addr).unwrap_or(self.open_bus),
+            _ => self.open_bus,
+        };
+        for (range, hook) in &self.hooks.writes {
+            if range.contains(&addr) {
+                data = (hook.lock().unwrap())(addr, old, data)?;
+            }
+        }
+        Some(data)
+    }
+
+    // Cycle-exact NMI for timing tests. The PPU's NMI only lands on instruction boundaries,
+    // since the PPU catches up once the instruction is done.
+    pub fn raise_nmi_at(&mut self, cpu_cycle: usize) {
+        self.nmi_edge_at = Some(cpu_cycle);
+    }
+
+    // level-triggered, unlike the NMI nothing is consumed when the CPU takes the interrupt
+    pub fn assert_irq(&mut self, source: IrqSource) {
+        self.assert_irq_at(source, self.cycles);
+    }
+
+    // for a device that knows which cycle of the current instruction it pulled the line on
+    pub fn assert_irq_at(&mut self, source: IrqSource, cpu_cycle: usize) {
+        self.irq_sources.insert(source);
+        let since = self.irq_low_since.map_or(cpu_cycle, |at| at.min(cpu_cycle));
+        self.irq_low_since = Some(since);
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            //mirror if needed
-            addr = addr % 0x4000;
+    pub fn deassert_irq(&mut self, source: IrqSource) {
+        self.irq_sources.remove(source);
+        if self.irq_sources.is_empty() {
+            self.irq_low_since = None;
         }
-        self.prg_rom[addr as usize]
     }
 
-    pub fn tick(&mut self, cycle: usize){
-        let ppu_cycle = 3 * cycle;
-        self.ppu.tick(ppu_cycle);
+    pub fn irq_line(&self) -> bool {
+        !self.irq_sources.is_empty()
+    }
+
+    // The DMC asks for its next sample byte. The CPU is halted for the fetch at its next
+    // instruction boundary, and the byte is left for the DMC to pick up with take_dmc_sample.
+    pub fn request_dmc_fetch(&mut self, addr: u16) {
+        self.dmc_fetch = Some(addr);
+    }
+
+    pub fn take_dmc_sample(&mut self) -> Option<u8> {
+        self.dmc_sample.take()
+    }
+
+    // work RAM at $6000-$7FFF, enabled and protected by the mapper
+    pub fn prg_ram(&self) -> &[u8] {
+        self.mapper.prg_ram()
+    }
+
+    // internal 2 KiB RAM, read without going through the memory map
+    pub fn cpu_ram(&self) -> &[u8] {
+        &self.cpu_vram
     }
 
-    pub fn pull_nmi_irq(&mut self) -> Option<u8>{
-        self.ppu.pull_nmi_irq()
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.frame_count()
+    }
+
+    pub fn ppu_register_writes(&self) -> u64 {
+        self.ppu_writes
+    }
+
+    pub fn sprite_overlay(&self) -> Vec<SpriteBox> {
+        self.ppu.sprite_overlay()
     }
 
     pub fn get_ppu_info(&self) -> (usize, usize){
         (self.ppu.clock_cycles, self.ppu.scan_lines)
     }
-}
 
-impl Mem for Bus {
-    fn mem_read(&mut self, addr: u16) -> u8 {
+    #[inline]
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = self.read_mapped(addr);
+        if self.hooks.reads.is_empty() {
+            data
+        } else {
+            self.hooked_read(addr, data)
+        }
+    }
+
+    #[inline]
+    fn write(&mut self, addr: u16, data: u8) {
+        if self.hooks.writes.is_empty() {
+            self.write_mapped(addr, data);
+        } else if let Some(data) = self.hooked_write(addr, data) {
+            self.write_mapped(addr, data);
+        }
+    }
+
+    #[inline]
+    fn count_ppu_write(&mut self, addr: u16) {
+        if (PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END).contains(&addr) || addr == 0x4014 {
+            self.ppu_writes += 1;
+        }
+    }
+
+    #[inline]
+    fn read_mapped(&mut self, addr: u16) -> u8 {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
@@ -91,9 +406,9 @@ impl Mem for Bus {
             }
             PPU_REGISTERS_MIRROR_START..=PPU_REGISTERS_MIRRORS_END => {
                 let _mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_read(_mirror_down_addr)
+                self.read_mapped(_mirror_down_addr)
             }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            PRG_RAM..=0xFFFF => self.mapper.read_prg(addr, self.open_bus),
 
             _ => {
                 println!("Ignoring mem access at {}", addr);
@@ -102,7 +417,8 @@ impl Mem for Bus {
         }
     }
 
-    fn mem_write(&mut self, addr: u16, data: u8) {
+    #[inline]
+    fn write_mapped(&mut self, addr: u16, data: u8) {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b11111111111;
@@ -117,18 +433,23 @@ impl Mem for Bus {
             0x2006 => self.ppu.write_to_ppu_addr(data),
             0x2007 => self.ppu.write_to_data(data),
             0x4014 => {
-                let full_addr = (data as u16) >> 8;
-                let mirror_down_addr = (full_addr & 0b00000111_11111111) as usize;
-
                 // Writing $XX will upload 256 bytes of data from CPU page $XX00–$XXFF to the internal PPU OAM
-                let mem_block = &self.cpu_vram[mirror_down_addr..mirror_down_addr+0xff];
-                self.ppu.write_oam_dma(mem_block)
+                let page = (data as u16) << 8;
+                let mut buffer = [0u8; 256];
+                for (i, byte) in buffer.iter_mut().enumerate() {
+                    let addr = page + i as u16;
+                    *byte = self.read(addr);
+                    self.log_access(addr, *byte, AccessKind::DmaRead);
+                    self.log_access(0x2004, *byte, AccessKind::DmaWrite);
+                }
+                self.ppu.write_oam_dma(&buffer);
+                self.oam_dma = true;
             }
             PPU_REGISTERS_MIRROR_START..=PPU_REGISTERS_MIRRORS_END => {
                 let _mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_write(_mirror_down_addr, data)
+                self.write_mapped(_mirror_down_addr, data)
             }
-            0x8000..=0xFFFF => panic!("Attempt to write to Cartridge ROM space: {:x}", addr),
+            PRG_RAM..=0xFFFF => self.mapper.write_prg(addr, data),
 
             _ => {
                 println!("Ignoring mem write-access at {}", addr);
@@ -137,10 +458,158 @@ impl Mem for Bus {
     }
 }
 
+impl Mem for Bus {
+    #[inline]
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let data = self.read(addr);
+        self.open_bus = data;
+        self.last_access = (addr, false);
+        self.log_access(addr, data, AccessKind::Read);
+        data
+    }
+
+    #[inline]
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
+        self.last_access = (addr, true);
+        self.log_access(addr, data, AccessKind::Write);
+        self.count_ppu_write(addr);
+        self.write(addr, data);
+    }
+}
+
+impl CpuBus for Bus {
+    // The throwaway read of indexed addressing. Side effects happen as for any read, but
+    // write-only registers drive nothing, so the bus keeps its last value.
+    fn mem_read_dummy(&mut self, addr: u16) -> u8 {
+        let data = if is_write_only(addr) {
+            self.open_bus
+        } else {
+            self.read(addr)
+        };
+        self.open_bus = data;
+        self.log_access(addr, data, AccessKind::DummyRead);
+        data
+    }
+
+    // the write half of a read-modify-write instruction that stores the unmodified value back
+    fn mem_write_dummy(&mut self, addr: u16, data: u8) {
+        self.log_access(addr, data, AccessKind::DummyWrite);
+        self.count_ppu_write(addr);
+        self.write(addr, data);
+    }
+
+    fn tick(&mut self, cycle: usize){
+        self.cycles += cycle;
+        if let Some(log) = self.access_log.as_mut() {
+            log.accesses_since_tick = 0;
+        }
+        let ppu_cycle = self.clock.advance_cpu(cycle as u64);
+        self.ppu.tick(ppu_cycle as usize);
+    }
+
+    fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // takes an NMI raised at or before `cpu_cycle`, which may lie inside the current instruction
+    fn pull_nmi_by(&mut self, cpu_cycle: usize) -> Option<u8> {
+        match self.nmi_edge_at {
+            Some(at) if at <= cpu_cycle => {
+                self.nmi_edge_at = None;
+                Some(1)
+            }
+            _ => self.ppu.pull_nmi_irq(),
+        }
+    }
+
+    // whether the line was already low at `cpu_cycle`
+    fn irq_line_by(&self, cpu_cycle: usize) -> bool {
+        matches!(self.irq_low_since, Some(at) if at <= cpu_cycle)
+    }
+
+    // The OAM DMA copy is done at once when $4014 is written; the CPU picks up the 513/514
+    // cycles it is halted for once the writing instruction is over.
+    fn take_oam_dma(&mut self) -> bool {
+        std::mem::replace(&mut self.oam_dma, false)
+    }
+
+    fn take_dmc_fetch(&mut self) -> Option<u16> {
+        self.dmc_fetch.take()
+    }
+
+    fn dmc_dma_read(&mut self, addr: u16) -> u8 {
+        let data = self.read(addr);
+        self.open_bus = data;
+        self.log_access(addr, data, AccessKind::DmaRead);
+        self.dmc_sample = Some(data);
+        data
+    }
+
+    fn last_access(&self) -> (u16, bool) {
+        self.last_access
+    }
+
+    // debugger/loader write: PRG-ROM is patched in place instead of hitting the mapper
+    // registers, and nothing is logged or left on the data bus
+    fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0xFFFF => {
+                if let Some(data) = self.hooked_write(addr, data) {
+                    self.mapper.patch_prg(addr, data);
+                }
+            }
+            _ => self.write(addr, data),
+        }
+    }
+
+    fn stack_page(&self) -> &[u8] {
+        &self.cpu_vram[0x0100..0x0200]
+    }
+
+    fn ppu_info(&self) -> (usize, usize) {
+        self.get_ppu_info()
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.mapper.prg_rom_offset(addr)
+    }
+
+    fn rom_sizes(&self) -> (usize, usize) {
+        (self.mapper.prg_rom_len(), self.ppu.chr_rom_len())
+    }
+
+    fn stored_byte(&self, addr: u16) -> Option<u8> {
+        match addr {
+            RAM..=RAM_MIRRORS_END => Some(self.cpu_vram[(addr & 0x07ff) as usize]),
+            PRG_RAM..=0x7FFF => self.mapper.peek_prg(addr),
+            _ => None,
+        }
+    }
+
+    fn restore_byte(&mut self, addr: u16, data: u8) {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0x07ff) as usize] = data,
+            PRG_RAM..=0xFFFF => self.mapper.patch_prg(addr, data),
+            _ => {}
+        }
+    }
+
+    // internal RAM and the cartridge; the registers in between have read side effects
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            RAM..=RAM_MIRRORS_END => Some(self.cpu_vram[(addr & 0x07ff) as usize]),
+            PRG_RAM..=0xFFFF => self.mapper.peek_prg(addr),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::cartridge::test;
+    use crate::cpu::{StopReason, CPU};
 
     #[test]
     fn test_mem_read_write_to_ram() {
@@ -148,4 +617,331 @@ mod test {
         bus.mem_write(0x01, 0x55);
         assert_eq!(bus.mem_read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_u16_access_at_page_and_address_space_boundaries() {
+        let rom = test::RomBuilder::new()
+            .code(0xc000, &[0x5a]) // also visible at $8000, the 16 KiB bank is mirrored
+            .irq_vector(0xabcd)
+            .build();
+        let mut cpu = CPU::new(Bus::new(Rom::new(&rom).unwrap()));
+
+        cpu.mem_write_u16(0x00ff, 0xbeef);
+        assert_eq!(cpu.bus.mem_read(0x00ff), 0xef);
+        assert_eq!(cpu.bus.mem_read(0x0100), 0xbe);
+        cpu.bus.mem_write_u16(0x00ff, 0x1234);
+        assert_eq!(cpu.mem_read_u16(0x00ff), 0x1234);
+
+        // PRG-RAM low byte, ROM high byte
+        cpu.bus.mem_write(0x7fff, 0x22);
+        assert_eq!(cpu.mem_read_u16(0x7fff), 0x5a22);
+
+        // ROM can't be written, so the top of memory is checked by reading only
+        cpu.bus.mem_write(0x0000, 0x12);
+        assert_eq!(cpu.mem_read_u16(0xfffe), 0xabcd);
+        assert_eq!(cpu.mem_read_u16(0xffff), 0x12ab);
+        assert_eq!(cpu.bus.mem_read_u16(0xffff), 0x12ab);
+    }
+
+    #[test]
+    fn test_unofficial_nop_abs_x_page_cross_cycle() {
+        // *NOP $02FF,X with X = 0 and X = 1, then the 7 cycles of the halting BRK
+        for (x, cycles) in [(0, 4 + 7), (1, 5 + 7)].iter() {
+            let mut cpu = CPU::new(Bus::new(test::test_rom()));
+            cpu.load(vec![0x1c, 0xff, 0x02, 0x00]).unwrap();
+            cpu.set_register_x(*x);
+            cpu.set_program_counter(0x0600);
+            assert_eq!(cpu.run().stop, StopReason::Brk);
+            assert_eq!(cpu.bus.cycles, *cycles);
+        }
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut bus = Bus::new(test::test_rom());
+        assert_eq!(bus.prg_ram().len(), 0x2000);
+        bus.mem_write(0x6000, 0x11);
+        bus.mem_write(0x7fff, 0x22);
+        assert_eq!(bus.mem_read(0x6000), 0x11);
+        assert_eq!(bus.mem_read(0x7fff), 0x22);
+    }
+
+    fn mmc3_rom() -> Rom {
+        Rom::new(&test::RomBuilder::new().mapper(4).prg_rom(&[0; 0x8000]).build()).unwrap()
+    }
+
+    #[test]
+    fn test_prg_ram_write_protect() {
+        let mut bus = Bus::new(mmc3_rom());
+        bus.mem_write(0x6123, 0x11);
+        bus.mem_write(0xa001, 0b1100_0000); // MMC3: enabled, write-protected
+        bus.mem_write(0x6123, 0x99);
+        assert_eq!(bus.mem_read(0x6123), 0x11);
+    }
+
+    #[test]
+    fn test_disabled_prg_ram_reads_open_bus() {
+        let mut cpu = CPU::new(Bus::new(mmc3_rom()));
+        cpu.bus.mem_write(0x6000, 0x11);
+        cpu.bus.mem_write(0xa001, 0); // MMC3: chip disabled
+        cpu.bus.mem_write(0x6000, 0x22); // ignored as well
+
+        // LDA $6000; BRK -- the last byte on the bus is the operand's high byte
+        cpu.load(vec![0xad, 0x00, 0x60, 0x00]).unwrap();
+        cpu.set_program_counter(0x0600);
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+        assert_eq!(cpu.register_a(), 0x60);
+
+        cpu.bus.mem_write(0xa001, 0b1000_0000);
+        assert_eq!(cpu.bus.mem_read(0x6000), 0x11);
+    }
+
+    #[test]
+    fn test_clone_forks_the_whole_machine() {
+        let mut cpu = CPU::new(Bus::new(mmc3_rom()));
+        // loop: LDA $FF; CLC; ADC $6000; STA $6000; DEX; BNE loop; BRK
+        cpu.load([0xa5, 0xff, 0x18, 0x6d, 0x00, 0x60, 0x8d, 0x00, 0x60, 0xca, 0xd0, 0xf4, 0x00])
+            .unwrap();
+        cpu.set_program_counter(0x0600);
+        cpu.set_register_x(4);
+        cpu.bus.mem_write(0x00ff, 1);
+        cpu.bus.mem_write(0x6000, 0);
+
+        // one pass through the loop, then fork
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.bus.mem_read(0x6000), 1);
+        let mut fork = cpu.clone();
+        fork.bus.mem_write(0x00ff, 3);
+
+        assert_eq!(fork.run().stop, StopReason::Brk);
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+        assert_eq!(fork.bus.mem_read(0x6000), 1 + 3 * 3);
+        assert_eq!(cpu.bus.mem_read(0x6000), 4);
+        assert_eq!(cpu.bus.mem_read(0x00ff), 1);
+    }
+
+    #[test]
+    fn test_machine_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Bus>();
+        assert_send::<PPU>();
+        assert_send::<CPU>();
+    }
+
+    #[test]
+    fn test_runs_on_a_worker_thread() {
+        let mut cpu = CPU::new(Bus::new(mmc3_rom()));
+        // loop: INX; BNE loop; INY; JMP loop
+        cpu.load([0xe8, 0xd0, 0xfd, 0xc8, 0x4c, 0x00, 0x06]).unwrap();
+        cpu.set_program_counter(0x0600);
+        let mut local = cpu.clone();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let worker = std::thread::spawn(move || {
+            for _ in 0..5000 {
+                cpu.step().unwrap();
+            }
+            tx.send((cpu.register_x(), cpu.register_y(), cpu.cycles())).unwrap();
+        });
+        let result = rx.recv().unwrap();
+        worker.join().unwrap();
+
+        for _ in 0..5000 {
+            local.step().unwrap();
+        }
+        assert_eq!(result, (local.register_x(), local.register_y(), local.cycles()));
+        assert_eq!((result.0, result.1), (187, 9));
+    }
+
+    #[test]
+    fn test_write_hook_freezes_ram() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        cpu.bus.mem_write(0x0010, 0x42);
+        cpu.bus.add_write_hook(0x0010..=0x0010, |_, old, _| Some(old));
+
+        // INC $10; INC $10; LDA $10; STA $11; INC $11; BRK
+        cpu.load([0xe6, 0x10, 0xe6, 0x10, 0xa5, 0x10, 0x85, 0x11, 0xe6, 0x11, 0x00])
+            .unwrap();
+        cpu.set_program_counter(0x0600);
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+        assert_eq!(cpu.register_a(), 0x42);
+        // the byte next to it is not frozen
+        assert_eq!(cpu.bus.mem_read(0x0011), 0x43);
+
+        cpu.bus.poke(0x0010, 0x00);
+        assert_eq!(cpu.bus.mem_read(0x0010), 0x42);
+
+        cpu.bus.clear_hooks();
+        cpu.bus.poke(0x0010, 0x00);
+        assert_eq!(cpu.bus.mem_read(0x0010), 0x00);
+    }
+
+    #[test]
+    fn test_write_hook_rewrites_and_vetoes() {
+        let mut bus = Bus::new(mmc3_rom());
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&seen);
+        bus.add_write_hook(0x6000..=0x6001, move |addr, old, new| {
+            log.lock().unwrap().push((addr, old, new));
+            if addr == 0x6000 {
+                Some(new.min(9))
+            } else {
+                None
+            }
+        });
+        bus.mem_write(0x6000, 5);
+        bus.mem_write(0x6000, 200);
+        bus.mem_write(0x6001, 7);
+        assert_eq!(bus.prg_ram()[..2], [9, 0]);
+        assert_eq!(*seen.lock().unwrap(), vec![(0x6000, 0, 5), (0x6000, 5, 200), (0x6001, 0, 7)]);
+    }
+
+    #[test]
+    fn test_read_hook_sees_cpu_and_dma_reads() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.mem_write(0x0203, 0x11);
+        let hits = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&hits);
+        bus.add_read_hook(0x0203..=0x0203, move |addr, value| {
+            log.lock().unwrap().push(addr);
+            value + 1
+        });
+
+        assert_eq!(bus.mem_read(0x0203), 0x12);
+        // OAM DMA from page 2 reads $0203 once more
+        bus.mem_write(0x4014, 0x02);
+        assert_eq!(*hits.lock().unwrap(), vec![0x0203, 0x0203]);
+        // a peek bypasses the hooks, it is not an access
+        assert_eq!(bus.peek(0x0203), Some(0x11));
+    }
+
+    #[test]
+    fn test_mapped_input_and_rng() {
+        let mut cpu = CPU::new(Bus::new(test::test_rom()));
+        let mut rng = 0x10u8..;
+        cpu.bus.map_read(0x00fe, move || rng.next().unwrap());
+        cpu.bus.map_read(0x00ff, || 0x77); // 'w'
+        let written = Arc::new(Mutex::new(vec![]));
+        let sink = Arc::clone(&written);
+        cpu.bus.map_write(0x00fd, move |data| sink.lock().unwrap().push(data));
+
+        // LDA $FE; STA $10; LDA $FE; STA $11; LDX $FF; STX $FD; BRK
+        cpu.load([0xa5, 0xfe, 0x85, 0x10, 0xa5, 0xfe, 0x85, 0x11, 0xa6, 0xff, 0x86, 0xfd, 0x00])
+            .unwrap();
+        cpu.bus.poke(0x00fe, 0x99);
+        cpu.set_program_counter(0x0600);
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+
+        assert_eq!(cpu.bus.cpu_ram()[0x10..0x12], [0x10, 0x11]);
+        assert_eq!(cpu.register_x(), 0x77);
+        assert_eq!(*written.lock().unwrap(), vec![0x77]);
+        assert_eq!(cpu.bus.cpu_ram()[0xfd], 0x00);
+        assert_eq!(cpu.bus.cpu_ram()[0xfe], 0x99);
+    }
+
+    #[test]
+    fn test_tick_follows_the_region_clock() {
+        let mut bus = Bus::new(test::test_rom());
+        assert_eq!(bus.region(), Region::Ntsc);
+        bus.tick(5);
+        assert_eq!(bus.get_ppu_info(), (15, 0));
+
+        let mut bus = Bus::new(test::test_rom());
+        bus.set_region(Region::Pal);
+        bus.tick(5);
+        assert_eq!(bus.get_ppu_info(), (16, 0));
+    }
+
+    #[test]
+    fn test_access_log_rmw_sequence() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.mem_write(0x15, 0x41);
+        // INC $10,X with X = 5
+        bus.mem_write(0x0600, 0xf6);
+        bus.mem_write(0x0601, 0x10);
+        bus.mem_write(0x0602, 0x00);
+        bus.enable_access_log(16, 0x0010..=0x001f);
+
+        let mut cpu = CPU::new(bus);
+        cpu.set_program_counter(0x0600);
+        cpu.set_register_x(5);
+        cpu.set_dummy_reads(true);
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+
+        let log = cpu.bus.take_access_log();
+        let kinds: Vec<(u16, u8, AccessKind)> = log.iter().map(|a| (a.addr, a.value, a.kind)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (0x15, 0x41, AccessKind::Read),
+                (0x15, 0x41, AccessKind::DummyWrite),
+                (0x15, 0x42, AccessKind::Write),
+            ]
+        );
+        assert!(log[0].cpu_cycle < log[1].cpu_cycle);
+        assert!(log[1].cpu_cycle < log[2].cpu_cycle);
+        assert!(cpu.bus.take_access_log().is_empty());
+    }
+
+    #[test]
+    fn test_access_log_oam_dma() {
+        let mut bus = Bus::new(test::test_rom());
+        for i in 0..256u16 {
+            bus.mem_write(0x0200 + i, i as u8);
+        }
+        bus.enable_access_log(1024, 0x0000..=0xffff);
+        bus.mem_write(0x4014, 0x02);
+
+        let log = bus.take_access_log();
+        assert_eq!(log.len(), 1 + 512);
+        assert_eq!((log[0].addr, log[0].kind), (0x4014, AccessKind::Write));
+        for i in 0..256 {
+            let read = log[1 + 2 * i];
+            let write = log[2 + 2 * i];
+            assert_eq!((read.addr, read.value, read.kind), (0x0200 + i as u16, i as u8, AccessKind::DmaRead));
+            assert_eq!((write.addr, write.value, write.kind), (0x2004, i as u8, AccessKind::DmaWrite));
+        }
+    }
+
+    #[test]
+    fn test_access_log_is_bounded() {
+        let mut bus = Bus::new(test::test_rom());
+        bus.enable_access_log(2, 0x0000..=0x07ff);
+        bus.mem_write(0x01, 1);
+        bus.mem_write(0x02, 2);
+        bus.mem_write(0x0800, 3); // outside of the filtered range
+        bus.mem_write(0x03, 3);
+
+        let addrs: Vec<u16> = bus.take_access_log().iter().map(|a| a.addr).collect();
+        assert_eq!(addrs, vec![0x02, 0x03]);
+    }
+
+    #[test]
+    fn test_heatmap_finds_the_hammered_address() {
+        let mut bus = Bus::new(test::test_rom());
+        // LDX #$40; loop: INC $10; DEX; BNE loop; BRK
+        let program = [0xa2, 0x40, 0xe6, 0x10, 0xca, 0xd0, 0xfb, 0x00];
+        for (i, byte) in program.iter().enumerate() {
+            bus.mem_write(0x0600 + i as u16, *byte);
+        }
+        bus.enable_heatmap();
+        let mut cpu = CPU::new(bus);
+        cpu.set_program_counter(0x0600);
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+
+        let heatmap = cpu.bus.heatmap().unwrap();
+        let hottest = heatmap.hottest(3);
+        assert_eq!((hottest[0].addr, hottest[0].reads, hottest[0].writes), (0x0010, 64, 64));
+        // opcode and operand fetches count as reads of where the code is
+        let reads: Vec<u32> = (0x0600..0x0608).map(|addr| heatmap.reads(addr)).collect();
+        assert_eq!(reads, vec![1, 1, 64, 64, 64, 64, 64, 1]);
+        assert_eq!(heatmap.writes(0x0602), 0);
+
+        cpu.bus.reset_heatmap();
+        assert!(cpu.bus.heatmap().unwrap().hottest(3).is_empty());
+        cpu.bus.disable_heatmap();
+        assert!(cpu.bus.heatmap().is_none());
+    }
 }
@@ -0,0 +1,265 @@
+// Shadow call stack rebuilt from JSR/RTS and interrupt entry/RTI.
+//
+// Each frame remembers the stack pointer at the time of the call. Since the 6502 stack grows
+// down, every frame whose sp_at_call is at or below the current SP after a return (or before a
+// new call) can no longer be live, so those frames are dropped. This keeps the shadow stack in
+// sync with games that pop their own return address or push fake ones and RTS into them.
+//
+// Frames leaving the stack are also tallied into a profile of the cycles spent per call target.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+    Subroutine,
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    pub return_addr: u16, // where execution resumes once the callee returns
+    pub target: u16,      // entry point of the callee
+    pub sp_at_call: u8,   // stack pointer before the return address was pushed
+    pub entry_cycle: u64, // CPU cycle the JSR or the interrupt sequence started on
+}
+
+// Cycles spent in the calls to `target` that have returned. Inclusive cycles run from the start
+// of the JSR to the end of the RTS and take in the callees; exclusive ones leave them out. A
+// recursive routine has its inner calls counted again in the outer ones' inclusive cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileEntry {
+    pub target: u16,
+    pub calls: u64,
+    pub inclusive_cycles: u64,
+    pub exclusive_cycles: u64,
+}
+
+#[derive(Clone)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+    callee_cycles: Vec<u64>, // for each frame, inclusive cycles of its callees so far
+    profile: HashMap<u16, ProfileEntry>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack {
+            frames: vec![],
+            callee_cycles: vec![],
+            profile: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, frame: CallFrame) {
+        self.resync(frame.sp_at_call, frame.entry_cycle);
+        self.frames.push(frame);
+        self.callee_cycles.push(0);
+    }
+
+    // called on RTS/RTI with the stack pointer they leave behind and the cycle they end on
+    pub fn pop(&mut self, sp_after_return: u8, now: u64) {
+        self.resync(sp_after_return, now);
+    }
+
+    // frames dropped here end at `now`, whether they returned or were abandoned
+    fn resync(&mut self, sp: u8, now: u64) {
+        while let Some(frame) = self.frames.last() {
+            if frame.sp_at_call > sp {
+                break;
+            }
+            let target = frame.target;
+            let inclusive = now.saturating_sub(frame.entry_cycle);
+            let exclusive = inclusive.saturating_sub(self.callee_cycles.pop().unwrap_or(0));
+            self.frames.pop();
+            if let Some(caller) = self.callee_cycles.last_mut() {
+                *caller += inclusive;
+            }
+            let entry = self.profile.entry(target).or_insert(ProfileEntry {
+                target,
+                calls: 0,
+                inclusive_cycles: 0,
+                exclusive_cycles: 0,
+            });
+            entry.calls += 1;
+            entry.inclusive_cycles += inclusive;
+            entry.exclusive_cycles += exclusive;
+        }
+    }
+
+    // most inclusive cycles first
+    pub fn profile_report(&self) -> Vec<ProfileEntry> {
+        let mut report: Vec<ProfileEntry> = self.profile.values().copied().collect();
+        report.sort_by_key(|entry| (std::cmp::Reverse(entry.inclusive_cycles), entry.target));
+        report
+    }
+
+    // outermost call first
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    // drops the profile as well
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.callee_cycles.clear();
+        self.profile.clear();
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        CallStack::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::CPU;
+    use crate::cpu::Mem;
+
+    fn cpu_with_tracking(program: &[u8]) -> CPU {
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        cpu.enable_call_tracking();
+        cpu.load(program).unwrap();
+        cpu.set_program_counter(0x0600);
+        cpu
+    }
+
+    #[test]
+    fn test_nested_calls_three_deep() {
+        let mut cpu = cpu_with_tracking(&[
+            0x20, 0x10, 0x06, // $0600: JSR $0610
+            0x00, //             $0603: BRK
+        ]);
+        cpu.mem_write(0x0610, 0x20); // $0610: JSR $0620
+        cpu.mem_write_u16(0x0611, 0x0620);
+        cpu.mem_write(0x0613, 0x60); // RTS
+        cpu.mem_write(0x0620, 0x20); // $0620: JSR $0630
+        cpu.mem_write_u16(0x0621, 0x0630);
+        cpu.mem_write(0x0623, 0x60); // RTS
+        cpu.mem_write(0x0630, 0xea); // $0630: NOP
+        cpu.mem_write(0x0631, 0x60); // RTS
+
+        let mut deepest: Vec<CallFrame> = vec![];
+        cpu.run_with_callback(|cpu| {
+            if cpu.program_counter() == 0x0630 {
+                deepest = cpu.call_stack().to_vec();
+            }
+        }).into_result().unwrap();
+
+        let targets: Vec<u16> = deepest.iter().map(|f| f.target).collect();
+        let returns: Vec<u16> = deepest.iter().map(|f| f.return_addr).collect();
+        assert_eq!(targets, vec![0x0610, 0x0620, 0x0630]);
+        assert_eq!(returns, vec![0x0603, 0x0613, 0x0623]);
+        assert!(cpu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn test_routine_dropping_its_return_address_resyncs() {
+        let mut cpu = cpu_with_tracking(&[
+            0x20, 0x10, 0x06, // $0600: JSR $0610
+            0x00, //             $0603: BRK (never reached)
+        ]);
+        // $0610: PLA; PLA; JMP $0620 -- discards its own return address
+        cpu.mem_write(0x0610, 0x68);
+        cpu.mem_write(0x0611, 0x68);
+        cpu.mem_write(0x0612, 0x4c);
+        cpu.mem_write_u16(0x0613, 0x0620);
+        // $0620: JSR $0630; BRK
+        cpu.mem_write(0x0620, 0x20);
+        cpu.mem_write_u16(0x0621, 0x0630);
+        cpu.mem_write(0x0623, 0x00);
+        // $0630: RTS
+        cpu.mem_write(0x0630, 0x60);
+
+        let mut at_inner: Vec<CallFrame> = vec![];
+        cpu.run_with_callback(|cpu| {
+            if cpu.program_counter() == 0x0630 {
+                at_inner = cpu.call_stack().to_vec();
+            }
+        }).into_result().unwrap();
+
+        // the stale frame for $0610 was replaced by the call made at the same stack depth
+        assert_eq!(at_inner.len(), 1);
+        assert_eq!(at_inner[0].target, 0x0630);
+        assert!(cpu.call_stack().is_empty());
+    }
+
+    #[test]
+    fn test_profile_of_nested_subroutines() {
+        let mut cpu = cpu_with_tracking(&[
+            0x20, 0x10, 0x06, // $0600: JSR $0610
+            0x00, //             $0603: BRK
+        ]);
+        // $0610: LDX #$03; JSR $0620; JSR $0620; RTS
+        for (i, byte) in [0xa2, 0x03, 0x20, 0x20, 0x06, 0x20, 0x20, 0x06, 0x60].iter().enumerate() {
+            cpu.mem_write(0x0610 + i as u16, *byte);
+        }
+        // $0620: NOP; NOP; RTS
+        for (i, byte) in [0xea, 0xea, 0x60].iter().enumerate() {
+            cpu.mem_write(0x0620 + i as u16, *byte);
+        }
+
+        let mut live = vec![];
+        cpu.run_with_callback(|cpu| {
+            if cpu.program_counter() == 0x0620 {
+                live = cpu.call_stack().to_vec();
+            }
+        }).into_result().unwrap();
+        assert_eq!(live[1].entry_cycle - live[0].entry_cycle, 6 + 2 + 16);
+
+        // the inner routine is JSR 6 + NOP 2 + NOP 2 + RTS 6 a call,
+        // the outer one JSR 6 + LDX 2 + RTS 6 plus the two inner calls
+        let report = cpu.profile_report();
+        let rows: Vec<(u16, u64, u64, u64)> = report
+            .iter()
+            .map(|e| (e.target, e.calls, e.inclusive_cycles, e.exclusive_cycles))
+            .collect();
+        assert_eq!(rows, vec![(0x0610, 1, 46, 14), (0x0620, 2, 32, 32)]);
+    }
+
+    #[test]
+    fn test_rts_into_a_pushed_address_keeps_the_tracker_in_sync() {
+        let mut cpu = cpu_with_tracking(&[
+            0xa9, 0x06, //       $0600: LDA #$06
+            0x48, //             $0602: PHA
+            0xa9, 0x08, //       $0603: LDA #$08
+            0x48, //             $0605: PHA
+            0x60, //             $0606: RTS to $0609, with nothing called
+            0x00, 0x00, //       $0607
+            0x20, 0x20, 0x06, // $0609: JSR $0620
+            0x00, //             $060C: BRK
+        ]);
+        // $0620: jump to $0630 through the stack
+        for (i, byte) in [0xa9, 0x06, 0x48, 0xa9, 0x2f, 0x48, 0x60].iter().enumerate() {
+            cpu.mem_write(0x0620 + i as u16, *byte);
+        }
+        cpu.mem_write(0x0630, 0x60); // RTS
+
+        let mut at_0630: Vec<CallFrame> = vec![];
+        cpu.run_with_callback(|cpu| {
+            if cpu.program_counter() == 0x0630 {
+                at_0630 = cpu.call_stack().to_vec();
+            }
+        }).into_result().unwrap();
+
+        // the fake return left the real one on the stack, so $0620 is still being run
+        let targets: Vec<u16> = at_0630.iter().map(|f| f.target).collect();
+        assert_eq!(targets, vec![0x0620]);
+        assert_eq!(cpu.program_counter(), 0x060d);
+        assert!(cpu.call_stack().is_empty());
+        // JSR 6, LDA/PHA twice 10, the fake RTS 6 and the real one 6
+        let report = cpu.profile_report();
+        assert_eq!(report.len(), 1);
+        let row = (report[0].target, report[0].calls, report[0].inclusive_cycles);
+        assert_eq!(row, (0x0620, 1, 28));
+    }
+}
@@ -1,24 +1,183 @@
+use crate::clock::Region;
+#[cfg(feature = "header-db")]
+use std::sync::Mutex;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const UNIF_TAG: [u8; 4] = *b"UNIF";
+const UNIF_HEADER_SIZE: usize = 32;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+const PRG_RAM_PAGE_SIZE: usize = 8192;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomError {
+    Malformed(String),
+    UnsupportedBoard(String), // a UNIF board name with no mapper number
+}
+
+impl std::fmt::Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RomError::Malformed(msg) => write!(f, "{}", msg),
+            RomError::UnsupportedBoard(name) => write!(f, "Unsupported UNIF board {}", name),
+        }
+    }
+}
+
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
     pub mapper: u8,
     pub screen_mirroring: Mirroring,
+    pub prg_ram_size: usize,
+    pub has_battery: bool,
+    pub region: Region,
+    prg_crc32: u32,
+    chr_crc32: u32,
+}
+
+// What a frontend shows for a loaded image, e.g. "mapper 0, 32K PRG, 8K CHR, NTSC"
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+    pub mapper: u8,
+    pub submapper: u8, // always 0 until NES 2.0 headers are supported
+    pub mirroring: Mirroring,
+    pub prg_size: usize,
+    pub chr_size: usize,
+    pub has_battery: bool,
+    pub region: Region,
+    pub prg_crc32: u32,
+    pub chr_crc32: u32,
+    pub overall_crc32: u32, // PRG followed by CHR, header and trainer excluded
+}
+
+impl std::fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let region = match self.region {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+        };
+        write!(
+            f,
+            "mapper {}, {}K PRG, {}K CHR, {}",
+            self.mapper,
+            self.prg_size / 1024,
+            self.chr_size / 1024,
+            region
+        )
+    }
+}
+
+// Corrected header fields for a known bad dump, keyed by the overall CRC32 of the image.
+// Fields left as None keep whatever the header says.
+#[cfg(feature = "header-db")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderOverride {
+    pub crc32: u32,
+    pub mapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub has_battery: Option<bool>,
+    pub region: Option<Region>,
+}
+
+// Entries are added as mis-headered dumps are reported
+#[cfg(feature = "header-db")]
+const HEADER_OVERRIDES: &[HeaderOverride] = &[];
+
+#[cfg(feature = "header-db")]
+lazy_static! {
+    static ref USER_HEADER_OVERRIDES: Mutex<Vec<HeaderOverride>> = Mutex::new(vec![]);
+}
+
+// Applies to every Rom loaded afterwards; takes precedence over the compiled-in table
+#[cfg(feature = "header-db")]
+pub fn register_header_override(entry: HeaderOverride) {
+    USER_HEADER_OVERRIDES.lock().unwrap().push(entry);
+}
+
+#[cfg(feature = "header-db")]
+fn find_header_override(crc32: u32) -> Option<HeaderOverride> {
+    let user = USER_HEADER_OVERRIDES.lock().unwrap();
+    user.iter()
+        .rev()
+        .chain(HEADER_OVERRIDES.iter())
+        .find(|entry| entry.crc32 == crc32)
+        .cloned()
+}
+
+// CRC-32/ISO-HDLC, the checksum ROM databases are keyed by
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
 }
 
 impl Rom {
+    // Header overrides are applied here, before Bus picks a mapper from the result
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
+        let mut rom = if raw.len() >= 4 && raw[0..4] == UNIF_TAG {
+            Rom::from_unif(raw).map_err(|e| e.to_string())?
+        } else {
+            Rom::from_ines(raw)?
+        };
+
+        #[cfg(feature = "header-db")]
+        {
+            if let Some(entry) = find_header_override(rom.crc32()) {
+                rom.mapper = entry.mapper.unwrap_or(rom.mapper);
+                rom.screen_mirroring = entry.mirroring.unwrap_or(rom.screen_mirroring);
+                rom.has_battery = entry.has_battery.unwrap_or(rom.has_battery);
+                rom.region = entry.region.unwrap_or(rom.region);
+            }
+        }
+        Ok(rom)
+    }
+
+    fn with_checksums(mut self) -> Rom {
+        self.prg_crc32 = crc32(&self.prg_rom);
+        self.chr_crc32 = crc32(&self.chr_rom);
+        self
+    }
+
+    pub fn crc32(&self) -> u32 {
+        crc32_update(self.prg_crc32, &self.chr_rom)
+    }
+
+    pub fn info(&self) -> RomInfo {
+        RomInfo {
+            mapper: self.mapper,
+            submapper: 0,
+            mirroring: self.screen_mirroring,
+            prg_size: self.prg_rom.len(),
+            chr_size: self.chr_rom.len(),
+            has_battery: self.has_battery,
+            region: self.region,
+            prg_crc32: self.prg_crc32,
+            chr_crc32: self.chr_crc32,
+            overall_crc32: self.crc32(),
+        }
+    }
+
+    fn from_ines(raw: &[u8]) -> Result<Rom, String> {
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
             return Err("File is not in iNES file format".to_string());
         }
 
@@ -41,19 +200,141 @@ impl Rom {
         let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
 
         let skip_trainer = raw[6] & 0b100 != 0;
+        let has_battery = raw[6] & 0b10 != 0;
+        // flags 9 bit 0; most dumps leave it clear regardless of the actual region
+        let region = if raw[9] & 1 != 0 { Region::Pal } else { Region::Ntsc };
 
         let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
 
+        // 0 means 8 KiB, for compatibility with images that predate the field
+        let prg_ram_size = raw.get(8).map_or(1, |&pages| pages.max(1)) as usize * PRG_RAM_PAGE_SIZE;
+
         Ok(Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
             chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
             mapper: mapper,
             screen_mirroring: screen_mirroring,
-        })
+            prg_ram_size,
+            has_battery,
+            region,
+            prg_crc32: 0,
+            chr_crc32: 0,
+        }
+        .with_checksums())
+    }
+
+    // UNIF: a 32 byte header followed by chunks of [4 byte ID][u32 LE length][data].
+    // PRG0-PRGF and CHR0-CHRF are concatenated in chunk-number order.
+    pub fn from_unif(raw: &[u8]) -> Result<Rom, RomError> {
+        if raw.len() < UNIF_HEADER_SIZE || raw[0..4] != UNIF_TAG {
+            return Err(RomError::Malformed("File is not in UNIF file format".to_string()));
+        }
+
+        let mut board: Option<String> = None;
+        let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut screen_mirroring = Mirroring::HORIZONTAL;
+        let mut has_battery = false;
+        let mut region = Region::Ntsc;
+
+        let mut pos = UNIF_HEADER_SIZE;
+        while pos < raw.len() {
+            if pos + 8 > raw.len() {
+                return Err(RomError::Malformed(format!("Truncated UNIF chunk header at {}", pos)));
+            }
+            let id = &raw[pos..pos + 4];
+            let len = u32::from_le_bytes([raw[pos + 4], raw[pos + 5], raw[pos + 6], raw[pos + 7]]) as usize;
+            let start = pos + 8;
+            let data = raw.get(start..start + len).ok_or_else(|| {
+                RomError::Malformed(format!("Truncated UNIF chunk {}", String::from_utf8_lossy(id)))
+            })?;
+
+            match id {
+                b"MAPR" => {
+                    let name = data.split(|b| *b == 0).next().unwrap_or(&[]);
+                    board = Some(String::from_utf8_lossy(name).into_owned());
+                }
+                b"MIRR" => {
+                    // 2/3 (single screen) and 5 (mapper controlled) are left to the mapper
+                    screen_mirroring = match data.first() {
+                        Some(1) => Mirroring::VERTICAL,
+                        Some(4) => Mirroring::FOUR_SCREEN,
+                        _ => Mirroring::HORIZONTAL,
+                    };
+                }
+                // the chunk's presence is the flag, its byte carries nothing
+                b"BATR" => has_battery = true,
+                // 0 NTSC, 1 PAL, 2 either
+                b"TVCI" => {
+                    if data.first() == Some(&1) {
+                        region = Region::Pal;
+                    }
+                }
+                _ => {
+                    let bank = (id[3] as char).to_digit(16);
+                    match (&id[0..3], bank) {
+                        (b"PRG", Some(n)) => prg_chunks[n as usize] = Some(data),
+                        (b"CHR", Some(n)) => chr_chunks[n as usize] = Some(data),
+                        // NAME, READ, DINF, CTRL, PCK*/CCK* checksums, ...
+                        _ => {}
+                    }
+                }
+            }
+            pos = start + len;
+        }
+
+        let board = board.ok_or_else(|| RomError::Malformed("UNIF file has no MAPR chunk".to_string()))?;
+        let mapper = unif_board_mapper(&board).ok_or(RomError::UnsupportedBoard(board))?;
+
+        let prg_rom: Vec<u8> = prg_chunks.iter().flatten().flat_map(|c| c.iter().copied()).collect();
+        let chr_rom: Vec<u8> = chr_chunks.iter().flatten().flat_map(|c| c.iter().copied()).collect();
+        if prg_rom.is_empty() {
+            return Err(RomError::Malformed("UNIF file has no PRG chunk".to_string()));
+        }
+
+        Ok(Rom {
+            prg_rom,
+            chr_rom,
+            mapper,
+            screen_mirroring,
+            prg_ram_size: PRG_RAM_PAGE_SIZE,
+            has_battery,
+            region,
+            prg_crc32: 0,
+            chr_crc32: 0,
+        }
+        .with_checksums())
     }
 }
 
+// UNIF names boards instead of numbering mappers. The NES-/HVC-/UNL-/BTL-/BMC- prefix only
+// tells who made the board, so it is ignored.
+fn unif_board_mapper(board: &str) -> Option<u8> {
+    let name = ["NES-", "HVC-", "UNL-", "BTL-", "BMC-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+
+    let mapper = match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => 0,
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SGROM" | "SKROM" | "SLROM" | "SL1ROM"
+        | "SNROM" | "SOROM" | "SUROM" | "SXROM" => 1,
+        "UNROM" | "UOROM" => 2,
+        "CNROM" => 3,
+        "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TKROM" | "TLROM" | "TL1ROM" | "TNROM"
+        | "TSROM" | "TVROM" => 4,
+        "EKROM" | "ELROM" | "ETROM" | "EWROM" => 5,
+        "AMROM" | "ANROM" | "AN1ROM" | "AOROM" => 7,
+        "PNROM" | "PEEOROM" => 9,
+        "CPROM" => 13,
+        "BNROM" => 34,
+        "GNROM" | "MHROM" => 66,
+        _ => return None,
+    };
+    Some(mapper)
+}
+
 pub mod test {
 
     use super::*;
@@ -83,6 +364,219 @@ pub mod test {
         result
     }
 
+    // Builds iNES images in memory so tests don't need binary ROM fixtures.
+    // Vectors and injected code land in the last PRG bank, which is mapped at $C000.
+    pub struct RomBuilder {
+        mapper: u8,
+        mirroring: Mirroring,
+        battery: bool,
+        trainer: Option<Vec<u8>>,
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        patches: Vec<(u16, Vec<u8>)>,
+    }
+
+    impl RomBuilder {
+        pub fn new() -> Self {
+            RomBuilder {
+                mapper: 0,
+                mirroring: Mirroring::HORIZONTAL,
+                battery: false,
+                trainer: None,
+                prg_rom: vec![],
+                chr_rom: vec![],
+                patches: vec![],
+            }
+        }
+
+        pub fn mapper(mut self, mapper: u8) -> Self {
+            self.mapper = mapper;
+            self
+        }
+
+        pub fn mirroring(mut self, mirroring: Mirroring) -> Self {
+            self.mirroring = mirroring;
+            self
+        }
+
+        pub fn battery(mut self, battery: bool) -> Self {
+            self.battery = battery;
+            self
+        }
+
+        pub fn trainer(mut self, trainer: [u8; 512]) -> Self {
+            self.trainer = Some(trainer.to_vec());
+            self
+        }
+
+        // appended as-is; the total is padded up to a multiple of 16 KiB on build
+        pub fn prg_rom(mut self, data: &[u8]) -> Self {
+            self.prg_rom.extend_from_slice(data);
+            self
+        }
+
+        // appended as-is; the total is padded up to a multiple of 8 KiB on build
+        pub fn chr_rom(mut self, data: &[u8]) -> Self {
+            self.chr_rom.extend_from_slice(data);
+            self
+        }
+
+        // places code at a CPU address in $C000-$FFFF and points the reset vector at it
+        pub fn code(self, addr: u16, code: &[u8]) -> Self {
+            self.patch(addr, code).reset_vector(addr)
+        }
+
+        pub fn reset_vector(self, addr: u16) -> Self {
+            self.patch(0xFFFC, &addr.to_le_bytes())
+        }
+
+        pub fn nmi_vector(self, addr: u16) -> Self {
+            self.patch(0xFFFA, &addr.to_le_bytes())
+        }
+
+        pub fn irq_vector(self, addr: u16) -> Self {
+            self.patch(0xFFFE, &addr.to_le_bytes())
+        }
+
+        fn patch(mut self, addr: u16, data: &[u8]) -> Self {
+            assert!(addr >= 0xC000, "{:04x} is outside of the last PRG bank", addr);
+            assert!(
+                addr as usize + data.len() <= 0x10000,
+                "patch at {:04x} runs past $FFFF",
+                addr
+            );
+            self.patches.push((addr, data.to_vec()));
+            self
+        }
+
+        pub fn build(&self) -> Vec<u8> {
+            let prg_banks = self.prg_rom.len().div_ceil(PRG_ROM_PAGE_SIZE).max(1);
+            let chr_banks = self.chr_rom.len().div_ceil(CHR_ROM_PAGE_SIZE);
+
+            let mut prg_rom = self.prg_rom.clone();
+            prg_rom.resize(prg_banks * PRG_ROM_PAGE_SIZE, 0);
+            let last_bank = (prg_banks - 1) * PRG_ROM_PAGE_SIZE;
+            for (addr, data) in self.patches.iter() {
+                let start = last_bank + (*addr as usize - 0xC000);
+                prg_rom[start..start + data.len()].copy_from_slice(data);
+            }
+
+            let mut chr_rom = self.chr_rom.clone();
+            chr_rom.resize(chr_banks * CHR_ROM_PAGE_SIZE, 0);
+
+            let mut flags_6 = (self.mapper & 0b1111) << 4;
+            match self.mirroring {
+                Mirroring::VERTICAL => flags_6 |= 0b1,
+                Mirroring::HORIZONTAL => {}
+                Mirroring::FOUR_SCREEN => flags_6 |= 0b1000,
+            }
+            if self.battery {
+                flags_6 |= 0b10;
+            }
+            if self.trainer.is_some() {
+                flags_6 |= 0b100;
+            }
+            let flags_7 = self.mapper & 0b1111_0000;
+
+            let mut header = NES_TAG.to_vec();
+            header.extend(&[prg_banks as u8, chr_banks as u8, flags_6, flags_7]);
+            header.resize(16, 0);
+
+            create_rom(TestRom {
+                header,
+                trainer: self.trainer.clone(),
+                pgp_rom: prg_rom,
+                chr_rom,
+            })
+        }
+    }
+
+    impl Default for RomBuilder {
+        fn default() -> Self {
+            RomBuilder::new()
+        }
+    }
+
+    // UNIF image with a MAPR chunk followed by the given chunks
+    #[cfg(test)]
+    fn unif(board: &str, chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut raw = UNIF_TAG.to_vec();
+        raw.extend(&7u32.to_le_bytes());
+        raw.resize(UNIF_HEADER_SIZE, 0);
+
+        let mut name = board.as_bytes().to_vec();
+        name.push(0);
+        let mapr: (&[u8; 4], Vec<u8>) = (b"MAPR", name);
+        for (id, data) in std::iter::once(&mapr).chain(chunks.iter()) {
+            raw.extend(id.iter());
+            raw.extend(&(data.len() as u32).to_le_bytes());
+            raw.extend(data);
+        }
+        raw
+    }
+
+    #[test]
+    fn test_unif_nrom_128_matches_ines() {
+        let prg: Vec<u8> = (0..PRG_ROM_PAGE_SIZE).map(|i| i as u8).collect();
+        let chr: Vec<u8> = (0..CHR_ROM_PAGE_SIZE).map(|i| (i / 3) as u8).collect();
+
+        let raw = unif(
+            "NES-NROM-128",
+            &[
+                (b"MIRR", vec![1]),
+                (b"CHR0", chr.clone()),
+                (b"BATR", vec![0]),
+                (b"PRG0", prg.clone()),
+            ],
+        );
+        let unif_rom = Rom::new(&raw).unwrap();
+
+        let ines = RomBuilder::new()
+            .mirroring(Mirroring::VERTICAL)
+            .prg_rom(&prg)
+            .chr_rom(&chr)
+            .build();
+        let ines_rom = Rom::new(&ines).unwrap();
+
+        assert_eq!(unif_rom.prg_rom, ines_rom.prg_rom);
+        assert_eq!(unif_rom.chr_rom, ines_rom.chr_rom);
+        assert_eq!(unif_rom.mapper, ines_rom.mapper);
+        assert_eq!(unif_rom.screen_mirroring, ines_rom.screen_mirroring);
+        assert_eq!(unif_rom.crc32(), ines_rom.crc32());
+        assert!(unif_rom.has_battery);
+    }
+
+    #[test]
+    fn test_unif_chunks_concatenate_in_bank_order() {
+        let raw = unif(
+            "UNL-TLROM",
+            &[(b"PRG1", vec![2; 4]), (b"PRG0", vec![1; 4]), (b"CHRA", vec![3; 2])],
+        );
+        let rom = Rom::from_unif(&raw).unwrap();
+        assert_eq!(rom.prg_rom, vec![1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(rom.chr_rom, vec![3, 3]);
+        assert_eq!(rom.mapper, 4);
+        assert_eq!(rom.screen_mirroring, Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_unif_errors() {
+        let unknown = unif("UNL-Sachen-74LS374N", &[(b"PRG0", vec![0; 16])]);
+        let err = Rom::new(&unknown).err().unwrap();
+        assert!(err.contains("UNL-Sachen-74LS374N"), "{}", err);
+        assert_eq!(
+            Rom::from_unif(&unknown).err(),
+            Some(RomError::UnsupportedBoard("UNL-Sachen-74LS374N".to_string()))
+        );
+
+        let mut truncated = unif("NES-NROM-128", &[(b"PRG0", vec![0; 16])]);
+        truncated.truncate(truncated.len() - 1);
+        assert!(Rom::from_unif(&truncated).is_err());
+
+        let no_prg = unif("NES-NROM-128", &[]);
+        assert!(matches!(Rom::from_unif(&no_prg), Err(RomError::Malformed(_))));
+    }
+
     pub fn test_rom() -> Rom {
         let test_rom = create_rom(TestRom {
             header: vec![
@@ -96,6 +590,60 @@ pub mod test {
         Rom::new(&test_rom).unwrap()
     }
 
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+
+        // reference values from zlib
+        let info = test_rom().info();
+        assert_eq!(info.prg_crc32, 0xAB91_DAE5);
+        assert_eq!(info.chr_crc32, 0x2B0F_AF01);
+        assert_eq!(info.overall_crc32, 0x9012_89B3);
+    }
+
+    #[test]
+    fn test_rom_info() {
+        let raw = RomBuilder::new()
+            .mapper(3)
+            .battery(true)
+            .prg_rom(&[0; 2 * PRG_ROM_PAGE_SIZE])
+            .chr_rom(&[0; CHR_ROM_PAGE_SIZE])
+            .build();
+        let info = Rom::new(&raw).unwrap().info();
+
+        assert_eq!(info.mapper, 3);
+        assert_eq!(info.submapper, 0);
+        assert!(info.has_battery);
+        assert_eq!(info.region, Region::Ntsc);
+        assert_eq!(info.to_string(), "mapper 3, 32K PRG, 8K CHR, NTSC");
+    }
+
+    #[cfg(feature = "header-db")]
+    #[test]
+    fn test_header_override_fixes_mis_headered_image() {
+        // a UxROM game dumped with an NROM header; the PRG bytes make its CRC unique to this test
+        let raw = RomBuilder::new()
+            .prg_rom(b"header override test")
+            .chr_rom(&[0; CHR_ROM_PAGE_SIZE])
+            .build();
+        let crc = Rom::new(&raw).unwrap().crc32();
+
+        register_header_override(HeaderOverride {
+            crc32: crc,
+            mapper: Some(2),
+            mirroring: Some(Mirroring::VERTICAL),
+            has_battery: None,
+            region: None,
+        });
+
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 2);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert!(!rom.has_battery);
+        assert_eq!(rom.crc32(), crc);
+    }
+
     #[test]
     fn test() {
         let test_rom = create_rom(TestRom {
@@ -149,6 +697,54 @@ pub mod test {
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
     }
 
+    #[test]
+    fn test_rom_builder() {
+        let raw = RomBuilder::new()
+            .mapper(2)
+            .mirroring(Mirroring::VERTICAL)
+            .battery(true)
+            .prg_rom(&[0xAA; PRG_ROM_PAGE_SIZE + 1]) // padded up to two banks
+            .chr_rom(&[0x55; 16])
+            .code(0xC010, &[0xa9, 0x01, 0x00])
+            .nmi_vector(0xC100)
+            .build();
+
+        assert_eq!(raw[4], 2);
+        assert_eq!(raw[5], 1);
+        assert_eq!(raw[6] & 0b10, 0b10); // battery
+
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 2);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom[15], 0x55);
+        assert_eq!(rom.chr_rom[16], 0);
+
+        let last_bank = &rom.prg_rom[PRG_ROM_PAGE_SIZE..];
+        assert_eq!(&last_bank[0x10..0x13], &[0xa9, 0x01, 0x00]);
+        assert_eq!(&last_bank[0x3FFA..], &[0x00, 0xC1, 0x10, 0xC0, 0x00, 0x00]);
+        assert_eq!(last_bank[0], 0xAA);
+        assert_eq!(last_bank[1], 0);
+    }
+
+    #[test]
+    fn test_rom_builder_trainer_and_high_mapper() {
+        let raw = RomBuilder::new()
+            .mapper(0x42)
+            .mirroring(Mirroring::FOUR_SCREEN)
+            .trainer([0xEE; 512])
+            .prg_rom(&[0x01; 4])
+            .build();
+
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 0x42);
+        assert_eq!(rom.screen_mirroring, Mirroring::FOUR_SCREEN);
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(&rom.prg_rom[0..5], &[0x01, 0x01, 0x01, 0x01, 0x00]);
+        assert!(rom.chr_rom.is_empty());
+    }
+
     #[test]
     fn test_nes2_is_not_supported() {
         let test_rom = create_rom(TestRom {
@@ -0,0 +1,107 @@
+use std::io::{self, Write};
+
+// FCEUX code/data log flags for a PRG-ROM byte. Bits 2-3 hold the 8 KiB CPU window the byte
+// was last used through ($8000, $A000, $C000 or $E000).
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const PCM: u8 = 0x40; // fetched by the DMC as a sample
+const BANK_BITS: u8 = 0b1100;
+
+// Which PRG-ROM bytes ran as code and which were read as data, in the layout FCEUX and Mesen
+// load: one flag byte per PRG-ROM byte, then one per CHR-ROM byte. CHR accesses are not
+// logged, so that part stays zero.
+#[derive(Clone)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr_len: usize,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_len: usize, chr_len: usize) -> Self {
+        CodeDataLog { prg: vec![0; prg_len], chr_len }
+    }
+
+    // `offset` into PRG-ROM, `addr` the CPU address it was reached through
+    #[inline]
+    pub fn mark(&mut self, offset: usize, addr: u16, flags: u8) {
+        if let Some(byte) = self.prg.get_mut(offset) {
+            let bank = (((addr >> 13) & 0b11) as u8) << 2;
+            *byte = (*byte & !BANK_BITS) | flags | bank;
+        }
+    }
+
+    // the PRG-ROM part
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn clear(&mut self) {
+        self.prg.iter_mut().for_each(|byte| *byte = 0);
+    }
+
+    // contents of a .cdl file for the ROM
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.prg.clone();
+        bytes.resize(self.prg.len() + self.chr_len, 0);
+        bytes
+    }
+
+    pub fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::RomBuilder;
+    use crate::cartridge::Rom;
+    use crate::cpu::{StopReason, CPU};
+
+    #[test]
+    fn test_bank_bits_follow_the_last_use() {
+        let mut log = CodeDataLog::new(0x8000, 0);
+        log.mark(0x10, 0xe010, CODE);
+        log.mark(0x10, 0x8010, DATA);
+        assert_eq!(log.prg()[0x10], CODE | DATA);
+        log.mark(0x10, 0xa010, DATA);
+        assert_eq!(log.prg()[0x10], CODE | DATA | 1 << 2);
+    }
+
+    #[test]
+    fn test_code_and_data_bytes() {
+        let rom = RomBuilder::new()
+            .code(
+                0xc000,
+                &[
+                    0xad, 0x10, 0xc0, // LDA $C010
+                    0xae, 0x00, 0xc0, // LDX $C000, its own opcode as data
+                    0x00, //             BRK
+                ],
+            )
+            .chr_rom(&[0; 0x2000])
+            .build();
+        let mut cpu = CPU::new(Bus::new(Rom::new(&rom).unwrap()));
+        cpu.enable_cdl();
+        cpu.reset();
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+
+        // the 16 KiB of PRG are mirrored at $C000, which is window 2
+        let (c000, e000) = (2 << 2, 3 << 2);
+        let code = CODE | c000;
+        let prg = cpu.cdl_data().unwrap();
+        assert_eq!(prg.len(), 0x4000);
+        assert_eq!(&prg[0x0000..0x0007], &[code | DATA, code, code, code, code, code, code]);
+        assert_eq!(&prg[0x0010..0x0012], &[DATA | c000, 0x00]);
+        // the reset vector was read through $E000-$FFFF
+        assert_eq!(&prg[0x3ffc..0x3ffe], &[DATA | e000, DATA | e000]);
+        assert_eq!(prg.iter().filter(|b| **b != 0).count(), 10);
+
+        let mut file = vec![];
+        cpu.cdl().unwrap().write_to(&mut file).unwrap();
+        assert_eq!(file.len(), 0x4000 + 0x2000);
+        assert_eq!(&file[..0x4000], prg);
+        assert!(file[0x4000..].iter().all(|b| *b == 0));
+    }
+}
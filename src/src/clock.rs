@@ -0,0 +1,166 @@
+// Every console clock is an integer division of one crystal, the master clock:
+//
+//          master clock    CPU    PPU    PPU dots per CPU cycle
+//   NTSC   21.477272 MHz   /12    /4     3
+//   PAL    26.601712 MHz   /16    /5     3.2
+//   Dendy  26.601712 MHz   /15    /5     3
+//
+// Tick counts are derived from the absolute master cycle count, so the fractional PAL ratio
+// never accumulates rounding drift.
+
+pub const DOTS_PER_SCANLINE: usize = 341;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn master_clock_hz(self) -> u64 {
+        match self {
+            Region::Ntsc => 21_477_272,
+            Region::Pal | Region::Dendy => 26_601_712,
+        }
+    }
+
+    pub fn cpu_divider(self) -> u64 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    pub fn ppu_divider(self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    // including the pre-render line
+    pub fn scanlines_per_frame(self) -> usize {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // Dendy keeps the NTSC post-render gap and puts its 50 extra lines after vblank starts
+    pub fn vblank_scanline(self) -> usize {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    pub fn frame_rate(self) -> f64 {
+        let dots_per_frame = (DOTS_PER_SCANLINE * self.scanlines_per_frame()) as f64;
+        self.master_clock_hz() as f64 / self.ppu_divider() as f64 / dots_per_frame
+    }
+}
+
+#[derive(Clone)]
+pub struct MasterClock {
+    region: Region,
+    master_cycles: u64,
+    cpu_cycles: u64,
+    ppu_cycles: u64,
+}
+
+impl MasterClock {
+    pub fn new(region: Region) -> Self {
+        MasterClock {
+            region,
+            master_cycles: 0,
+            cpu_cycles: 0,
+            ppu_cycles: 0,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn master_cycles(&self) -> u64 {
+        self.master_cycles
+    }
+
+    pub fn cpu_cycles(&self) -> u64 {
+        self.cpu_cycles
+    }
+
+    pub fn ppu_cycles(&self) -> u64 {
+        self.ppu_cycles
+    }
+
+    // returns the (cpu, ppu) ticks that became due
+    pub fn advance(&mut self, master_cycles: u64) -> (u64, u64) {
+        self.master_cycles += master_cycles;
+        let cpu_total = self.master_cycles / self.region.cpu_divider();
+        let ppu_total = self.master_cycles / self.region.ppu_divider();
+
+        let due = (cpu_total - self.cpu_cycles, ppu_total - self.ppu_cycles);
+        self.cpu_cycles = cpu_total;
+        self.ppu_cycles = ppu_total;
+        due
+    }
+
+    // the CPU drives the bus, so it advances the clock in whole CPU cycles; returns the PPU ticks due
+    pub fn advance_cpu(&mut self, cpu_cycles: u64) -> u64 {
+        let (_, ppu) = self.advance(cpu_cycles * self.region.cpu_divider());
+        ppu
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MASTER_CYCLES: u64 = 10_000_000;
+
+    #[test]
+    fn test_no_drift_over_ten_million_master_cycles() {
+        for region in [Region::Ntsc, Region::Pal, Region::Dendy].iter() {
+            let mut clock = MasterClock::new(*region);
+            let (mut cpu, mut ppu) = (0, 0);
+            // uneven steps, so both dividers are crossed at every possible phase
+            let mut step = 1;
+            while clock.master_cycles() < MASTER_CYCLES {
+                let (c, p) = clock.advance(step.min(MASTER_CYCLES - clock.master_cycles()));
+                cpu += c;
+                ppu += p;
+                step = step % 7 + 1;
+            }
+
+            assert_eq!(cpu, MASTER_CYCLES / region.cpu_divider(), "{:?}", region);
+            assert_eq!(ppu, MASTER_CYCLES / region.ppu_divider(), "{:?}", region);
+        }
+    }
+
+    #[test]
+    fn test_ppu_ticks_per_cpu_cycle() {
+        let mut ntsc = MasterClock::new(Region::Ntsc);
+        assert_eq!(ntsc.advance_cpu(1), 3);
+
+        // PAL alternates 3 and 4 dots to average 3.2
+        let mut pal = MasterClock::new(Region::Pal);
+        let dots: Vec<u64> = (0..5).map(|_| pal.advance_cpu(1)).collect();
+        assert_eq!(dots, vec![3, 3, 3, 3, 4]);
+        assert_eq!(pal.ppu_cycles(), 16);
+
+        let mut dendy = MasterClock::new(Region::Dendy);
+        assert_eq!(dendy.advance_cpu(1_000), 3_000);
+        assert_eq!(dendy.cpu_cycles(), 1_000);
+    }
+
+    #[test]
+    fn test_frame_rates() {
+        assert!((Region::Ntsc.frame_rate() - 60.1).abs() < 0.01);
+        assert!((Region::Pal.frame_rate() - 50.007).abs() < 0.001);
+        assert!((Region::Dendy.frame_rate() - 50.007).abs() < 0.001);
+    }
+}
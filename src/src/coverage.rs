@@ -0,0 +1,210 @@
+const ADDRESS_SPACE: usize = 0x10000;
+const PRG_ROM_START: u16 = 0x8000;
+
+bitflags! {
+    // How the CPU used an address. The bits are those of the exported format.
+    pub struct CoverageFlags: u8 {
+        const EXECUTE = 0b001; // fetched as an opcode
+        const READ    = 0b010; // read as data, operand bytes excluded
+        const WRITE   = 0b100;
+    }
+}
+
+// One set of CoverageFlags per CPU address
+#[derive(Clone)]
+pub struct CoverageMap {
+    flags: Box<[CoverageFlags]>,
+}
+
+impl CoverageMap {
+    pub fn new() -> Self {
+        CoverageMap {
+            flags: vec![CoverageFlags::empty(); ADDRESS_SPACE].into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    pub fn mark(&mut self, addr: u16, flags: CoverageFlags) {
+        self.flags[addr as usize] |= flags;
+    }
+
+    pub fn flags(&self, addr: u16) -> CoverageFlags {
+        self.flags[addr as usize]
+    }
+
+    pub fn is_executed(&self, addr: u16) -> bool {
+        self.flags(addr).contains(CoverageFlags::EXECUTE)
+    }
+
+    pub fn is_read(&self, addr: u16) -> bool {
+        self.flags(addr).contains(CoverageFlags::READ)
+    }
+
+    pub fn is_written(&self, addr: u16) -> bool {
+        self.flags(addr).contains(CoverageFlags::WRITE)
+    }
+
+    pub fn executed_count(&self) -> usize {
+        self.flags.iter().filter(|flags| flags.contains(CoverageFlags::EXECUTE)).count()
+    }
+
+    // share of executed addresses within [start, end], in percent; reversed bounds are swapped
+    pub fn percentage(&self, start: u16, end: u16) -> f64 {
+        let (start, end) = (start.min(end), start.max(end));
+        let executed = (start..=end).filter(|addr| self.is_executed(*addr)).count();
+        let total = (end - start) as usize + 1;
+        executed as f64 * 100.0 / total as f64
+    }
+
+    pub fn prg_rom_percentage(&self) -> f64 {
+        self.percentage(PRG_ROM_START, 0xFFFF)
+    }
+
+    // addresses executed in self but not in other
+    pub fn diff(&self, other: &CoverageMap) -> Vec<u16> {
+        (0..ADDRESS_SPACE)
+            .filter(|&addr| self.is_executed(addr as u16) && !other.is_executed(addr as u16))
+            .map(|addr| addr as u16)
+            .collect()
+    }
+
+    // adds what other saw, e.g. to sum up several runs of a test ROM
+    pub fn merge(&mut self, other: &CoverageMap) {
+        for (mine, theirs) in self.flags.iter_mut().zip(other.flags.iter()) {
+            *mine |= *theirs;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.flags.iter_mut().for_each(|flags| *flags = CoverageFlags::empty());
+    }
+
+    // Export format: 64 KiB, the CoverageFlags bits of address N at offset N
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.flags.iter().map(|flags| flags.bits()).collect()
+    }
+
+    // None unless `bytes` is exactly 64 KiB; unknown bits are dropped
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != ADDRESS_SPACE {
+            return None;
+        }
+        let flags = bytes.iter().map(|b| CoverageFlags::from_bits_truncate(*b)).collect();
+        Some(CoverageMap { flags })
+    }
+}
+
+impl Default for CoverageMap {
+    fn default() -> Self {
+        CoverageMap::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::{CpuBus, CPU};
+
+    #[test]
+    fn test_mark_and_diff() {
+        let mut a = CoverageMap::new();
+        let mut b = CoverageMap::new();
+        a.mark(0x0000, CoverageFlags::EXECUTE);
+        a.mark(0x8001, CoverageFlags::EXECUTE);
+        a.mark(0xFFFF, CoverageFlags::EXECUTE);
+        b.mark(0x8001, CoverageFlags::EXECUTE);
+        // data accesses are not executions
+        a.mark(0x8000, CoverageFlags::READ | CoverageFlags::WRITE);
+
+        assert!(a.is_executed(0xFFFF));
+        assert!(!a.is_executed(0x8000));
+        assert_eq!(a.executed_count(), 3);
+        assert_eq!(a.diff(&b), vec![0x0000, 0xFFFF]);
+        assert!(b.diff(&a).is_empty());
+        assert_eq!(a.percentage(0x8000, 0x8003), 25.0);
+        assert_eq!(a.percentage(0x8003, 0x8000), 25.0);
+        assert_eq!(a.percentage(0xFFFF, 0xFFFF), 100.0);
+    }
+
+    #[test]
+    fn test_branch_not_taken_is_not_covered() {
+        let bus = Bus::new(test_rom());
+        let mut cpu = CPU::new(bus);
+        cpu.enable_coverage();
+        // LDA #$01; BEQ +3 (never taken); LDX #$05; BRK; LDY #$07; BRK
+        cpu.load_and_run(vec![0xa9, 0x01, 0xf0, 0x03, 0xa2, 0x05, 0x00, 0xa0, 0x07, 0x00]).unwrap();
+
+        let coverage = cpu.coverage().unwrap();
+        for addr in [0x0600, 0x0602, 0x0604, 0x0606].iter() {
+            assert!(coverage.is_executed(*addr), "{:04x} should be covered", addr);
+        }
+        // operand bytes are not opcode fetches
+        assert!(!coverage.is_executed(0x0601));
+        assert!(!coverage.is_executed(0x0607));
+        assert!(!coverage.is_executed(0x0609));
+        assert_eq!(coverage.executed_count(), 4);
+    }
+
+    // LDA $10; BEQ +3; LDX #$05; BRK; LDY #$07; STY $11; BRK
+    const BRANCHY: [u8; 12] = [
+        0xa5, 0x10, 0xf0, 0x03, 0xa2, 0x05, 0x00, 0xa0, 0x07, 0x84, 0x11, 0x00,
+    ];
+
+    fn run_branchy(cpu: &mut CPU, zero_page_10: u8) {
+        cpu.bus.poke(0x10, zero_page_10);
+        cpu.load_and_run(BRANCHY).unwrap();
+    }
+
+    #[test]
+    fn test_both_branch_directions_add_up() {
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        cpu.enable_coverage();
+        run_branchy(&mut cpu, 1);
+        let not_taken = cpu.coverage().unwrap().clone();
+        let executed = |map: &CoverageMap| -> Vec<u16> {
+            (0x0600..0x060c).filter(|a| map.is_executed(*a)).collect()
+        };
+        assert_eq!(executed(&not_taken), vec![0x0600, 0x0602, 0x0604, 0x0606]);
+        assert_eq!(not_taken.flags(0x10), CoverageFlags::READ);
+        assert_eq!(not_taken.flags(0x11), CoverageFlags::empty());
+        // operand bytes are not data reads
+        assert_eq!(not_taken.flags(0x0601), CoverageFlags::empty());
+
+        run_branchy(&mut cpu, 0);
+        let both = cpu.coverage().unwrap();
+        assert_eq!(
+            executed(both),
+            vec![0x0600, 0x0602, 0x0604, 0x0606, 0x0607, 0x0609, 0x060b]
+        );
+        assert!(both.is_written(0x11) && !both.is_read(0x11));
+
+        // the same union from two machines
+        let mut other = CPU::new(Bus::new(test_rom()));
+        other.enable_coverage();
+        run_branchy(&mut other, 0);
+        assert_eq!(
+            executed(other.coverage().unwrap()),
+            vec![0x0600, 0x0602, 0x0607, 0x0609, 0x060b]
+        );
+        let mut merged = not_taken.clone();
+        merged.merge(other.coverage().unwrap());
+        assert_eq!(merged.to_bytes(), both.to_bytes());
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let mut map = CoverageMap::new();
+        map.mark(0x0000, CoverageFlags::EXECUTE);
+        map.mark(0x0200, CoverageFlags::READ | CoverageFlags::WRITE);
+        map.mark(0xFFFF, CoverageFlags::WRITE);
+        let bytes = map.to_bytes();
+        assert_eq!(bytes.len(), 0x10000);
+        assert_eq!((bytes[0x0000], bytes[0x0200], bytes[0xFFFF], bytes[0x0001]), (1, 6, 4, 0));
+
+        let back = CoverageMap::from_bytes(&bytes).unwrap();
+        assert_eq!(back.to_bytes(), bytes);
+        assert!(CoverageMap::from_bytes(&bytes[1..]).is_none());
+    }
+}
@@ -0,0 +1,129 @@
+use crate::cpu::{CpuBus, CpuFlags, CpuVariant, CPU};
+use crate::flat_memory::FlatMemory;
+
+// Puts a CPU straight into the state a test needs instead of running code to get there.
+// Anything not set keeps the power-up value of CPU::with_bus. Memory is written in call
+// order through the bus's poke, so where two .mem() calls overlap the later one wins.
+pub struct CpuBuilder<M = FlatMemory> {
+    cpu: CPU<M>,
+}
+
+impl CpuBuilder {
+    pub fn new() -> Self {
+        CpuBuilder::with_bus(FlatMemory::new())
+    }
+}
+
+impl Default for CpuBuilder {
+    fn default() -> Self {
+        CpuBuilder::new()
+    }
+}
+
+impl<M: CpuBus> CpuBuilder<M> {
+    pub fn with_bus(bus: M) -> Self {
+        CpuBuilder {
+            cpu: CPU::with_bus(bus),
+        }
+    }
+
+    pub fn a(mut self, value: u8) -> Self {
+        self.cpu.set_register_a(value);
+        self
+    }
+
+    pub fn x(mut self, value: u8) -> Self {
+        self.cpu.set_register_x(value);
+        self
+    }
+
+    pub fn y(mut self, value: u8) -> Self {
+        self.cpu.set_register_y(value);
+        self
+    }
+
+    // replaces P as a whole
+    pub fn flags(mut self, flags: CpuFlags) -> Self {
+        self.cpu.set_status(flags.bits());
+        self
+    }
+
+    pub fn pc(mut self, addr: u16) -> Self {
+        self.cpu.set_program_counter(addr);
+        self
+    }
+
+    pub fn sp(mut self, value: u8) -> Self {
+        self.cpu.set_stack_pointer(value);
+        self
+    }
+
+    pub fn variant(mut self, variant: CpuVariant) -> Self {
+        self.cpu.set_variant(variant);
+        self
+    }
+
+    // bytes running past $FFFF wrap around to $0000
+    pub fn mem(mut self, addr: u16, bytes: &[u8]) -> Self {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.cpu.bus.poke(addr.wrapping_add(i as u16), *byte);
+        }
+        self
+    }
+
+    pub fn build(self) -> CPU<M> {
+        self.cpu
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_defaults_match_power_up() {
+        let cpu = CpuBuilder::new().build();
+        let fresh = CPU::with_bus(FlatMemory::new());
+        assert_eq!(
+            (cpu.register_a(), cpu.register_x(), cpu.register_y()),
+            (fresh.register_a(), fresh.register_x(), fresh.register_y())
+        );
+        assert_eq!((cpu.program_counter(), cpu.stack_pointer()), (0x0000, 0xfd));
+        assert_eq!(cpu.status(), 0b0010_0100);
+        assert_eq!(cpu.variant(), CpuVariant::Nmos6502);
+        assert!(cpu.bus.data().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_later_mem_writes_win() {
+        let mut cpu = CpuBuilder::new()
+            .mem(0x0010, &[0xde, 0xad, 0xbe, 0xef])
+            .mem(0x0012, &[0x11])
+            .mem(0xffff, &[0x22, 0x33])
+            .build();
+        assert_eq!(cpu.mem_read_u16(0x0010), 0xadde);
+        assert_eq!(cpu.mem_read_u16(0x0012), 0xef11);
+        assert_eq!((cpu.mem_read(0xffff), cpu.mem_read(0x0000)), (0x22, 0x33));
+    }
+
+    #[test]
+    fn test_state_is_ready_to_step() {
+        // ADC $10,X
+        let mut cpu = CpuBuilder::new()
+            .a(0x10)
+            .x(0xff)
+            .y(0x01)
+            .flags(CpuFlags::CARRY)
+            .pc(0x8000)
+            .sp(0xf0)
+            .mem(0x8000, &[0x75, 0x10])
+            .mem(0x000f, &[0x05])
+            .build();
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a(), 0x16);
+        assert_eq!(cpu.program_counter(), 0x8002);
+        assert_eq!((cpu.register_y(), cpu.stack_pointer()), (0x01, 0xf0));
+        assert_eq!(cpu.status(), 0);
+    }
+}
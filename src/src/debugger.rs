@@ -0,0 +1,458 @@
+// A small debugger over the public CPU API: parsing a command line and running it, and watches
+// sampled as frames run.
+// `cargo run --example debugger -- game.nes` reads commands from stdin, one per line:
+//
+//   s               step one instruction
+//   sb              step back one instruction
+//   c               continue to a breakpoint, a watchpoint, BRK or an error
+//   b ADDR          break before the instruction at ADDR
+//   w ADDR          stop after an instruction writes to ADDR
+//   m ADDR [LEN]    hex dump LEN bytes (16) from ADDR
+//   d [ADDR] [N]    disassemble N instructions (10) from ADDR, the PC by default
+//   r               registers, flags and the top of the stack
+//   reset           press reset
+//   q               quit
+//
+// Addresses are hex, with or without a `$`. Lengths and counts are decimal unless they start
+// with `$`.
+
+use crate::bus::Bus;
+use crate::cpu::{CpuBus, CpuError, Mem, RunExit, StopReason, CPU};
+use crate::disasm;
+use crate::journal::DEFAULT_JOURNAL_LEN;
+use crate::trace::trace_human;
+use std::io::{self, Write};
+
+const DUMP_LEN: usize = 16;
+const DISASM_COUNT: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Step,
+    StepBack,
+    Continue,
+    Break(u16),
+    Watch(u16),
+    Memory { addr: u16, len: usize },
+    Disassemble { addr: Option<u16>, count: usize },
+    Registers,
+    Reset,
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or_else(|| "empty command".to_string())?;
+        let args: Vec<&str> = words.collect();
+        let max_args = match name {
+            "s" | "sb" | "c" | "r" | "reset" | "h" | "help" | "q" => 0,
+            "b" | "w" => 1,
+            "m" | "d" => 2,
+            _ => return Err(format!("unknown command: {}, h for help", name)),
+        };
+        if args.len() > max_args {
+            return Err(format!("too many arguments to {}", name));
+        }
+        let addr = |i: usize| match args.get(i) {
+            Some(text) => parse_addr(text),
+            None => Err(format!("{} needs an address", name)),
+        };
+        let count = |i: usize, default: usize| match args.get(i) {
+            Some(text) => parse_count(text),
+            None => Ok(default),
+        };
+        Ok(match name {
+            "s" => Command::Step,
+            "sb" => Command::StepBack,
+            "c" => Command::Continue,
+            "b" => Command::Break(addr(0)?),
+            "w" => Command::Watch(addr(0)?),
+            "m" => Command::Memory { addr: addr(0)?, len: count(1, DUMP_LEN)? },
+            "d" => Command::Disassemble {
+                addr: if args.is_empty() { None } else { Some(addr(0)?) },
+                count: count(1, DISASM_COUNT)?,
+            },
+            "r" => Command::Registers,
+            "reset" => Command::Reset,
+            "h" | "help" => Command::Help,
+            _ => Command::Quit,
+        })
+    }
+}
+
+// `$C000`, `c000` or `C000`
+pub fn parse_addr(text: &str) -> Result<u16, String> {
+    let hex = text.strip_prefix('$').unwrap_or(text);
+    if hex.is_empty() || hex.len() > 4 {
+        return Err(format!("bad address: {}", text));
+    }
+    u16::from_str_radix(hex, 16).map_err(|_| format!("bad address: {}", text))
+}
+
+// `16`, or `$10` in hex
+pub fn parse_count(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix('$') {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("bad count: {}", text))
+}
+
+fn sample(watches: &mut [Watch], cpu: &CPU) {
+    let frame = cpu.bus.frame_count();
+    for watch in watches.iter_mut() {
+        let value = (watch.expr)(cpu);
+        watch.series.push((frame, value));
+    }
+}
+
+// The disassembler reads through Mem, which has side effects on the bus; this one only peeks
+// and shows 00 where peeking isn't possible
+struct Peek<'a>(&'a Bus);
+
+impl Mem for Peek<'_> {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.0.peek(addr).unwrap_or(0)
+    }
+
+    fn mem_write(&mut self, _addr: u16, _data: u8) {}
+}
+
+// When run_frame samples the watches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WatchEvery {
+    // as vblank starts
+    #[default]
+    Frame,
+    // after each step that moved the PPU onto another scanline
+    Scanline,
+}
+
+type WatchExpr = Box<dyn FnMut(&CPU) -> i64 + Send>;
+
+struct Watch {
+    name: String,
+    expr: WatchExpr,
+    series: Vec<(u64, i64)>, // frame, value
+}
+
+pub struct Debugger {
+    pub cpu: CPU,
+    watches: Vec<Watch>,
+    watch_every: WatchEvery,
+}
+
+impl Debugger {
+    pub fn new(mut cpu: CPU) -> Self {
+        cpu.enable_step_back(DEFAULT_JOURNAL_LEN);
+        Debugger { cpu, watches: Vec::new(), watch_every: WatchEvery::default() }
+    }
+
+    // Samples `expr` as run_frame goes, without stopping, e.g. `|cpu| cpu.bus.cpu_ram()[0x5a]
+    // as i64` for a lives counter. A watch already called `name` is replaced, its series lost.
+    pub fn add_watch<F>(&mut self, name: &str, expr: F)
+    where
+        F: FnMut(&CPU) -> i64 + Send + 'static,
+    {
+        self.remove_watch(name);
+        self.watches.push(Watch { name: name.to_string(), expr: Box::new(expr), series: vec![] });
+    }
+
+    pub fn remove_watch(&mut self, name: &str) {
+        self.watches.retain(|watch| watch.name != name);
+    }
+
+    // (frame, value) samples of the watch called `name`, in order. Frames count up as vblank
+    // starts; sampled every scanline, a frame has a sample for each line.
+    pub fn watch_series(&self, name: &str) -> Option<&[(u64, i64)]> {
+        let watch = self.watches.iter().find(|watch| watch.name == name)?;
+        Some(&watch.series)
+    }
+
+    pub fn set_watch_every(&mut self, every: WatchEvery) {
+        self.watch_every = every;
+    }
+
+    // Runs the CPU to the next vblank, sampling the watches on the way
+    pub fn run_frame(&mut self) -> RunExit {
+        let watches = &mut self.watches;
+        let exit = match self.watch_every {
+            WatchEvery::Frame => self.cpu.run_frame(),
+            WatchEvery::Scanline => {
+                let mut line = self.cpu.get_ppu_info().1;
+                self.cpu.run_frame_with(|cpu| {
+                    let (_, now) = cpu.get_ppu_info();
+                    if now != line {
+                        line = now;
+                        sample(watches, cpu);
+                    }
+                })
+            }
+        };
+        if exit == RunExit::FrameDone && self.watch_every == WatchEvery::Frame {
+            sample(watches, &self.cpu);
+        }
+        exit
+    }
+
+    // Runs `command`, writing what it shows to `out`; false once it quits
+    pub fn execute(&mut self, command: Command, out: &mut dyn Write) -> io::Result<bool> {
+        match command {
+            Command::Step => {
+                let mut result = self.cpu.step();
+                // `s` always moves, even onto a breakpoint's instruction
+                if let Err(CpuError::Breakpoint { .. }) = result {
+                    result = self.cpu.step();
+                }
+                match result {
+                    Ok(Some(_)) => {}
+                    Ok(None) => writeln!(out, "BRK stopped the CPU")?,
+                    Err(e) => writeln!(out, "{}", e)?,
+                }
+                self.show_location(out)?;
+            }
+            Command::StepBack => {
+                match self.cpu.step_back() {
+                    Some(back) if !back.reversible => {
+                        writeln!(out, "the step touched registers, the PPU may not match")?
+                    }
+                    Some(_) => {}
+                    None => writeln!(out, "nothing to step back over")?,
+                }
+                self.show_location(out)?;
+            }
+            Command::Continue => {
+                let summary = self.cpu.run();
+                match summary.stop {
+                    StopReason::Brk => writeln!(out, "BRK stopped the CPU")?,
+                    StopReason::Jammed => writeln!(out, "CPU jammed")?,
+                    StopReason::CallbackBreak => {}
+                    StopReason::Breakpoint { addr, hits } => {
+                        writeln!(out, "breakpoint at ${:04X}, hit {}", addr, hits)?
+                    }
+                    StopReason::Watchpoint { addr, pc } => {
+                        writeln!(out, "watchpoint: ${:04X} written at ${:04X}", addr, pc)?
+                    }
+                    StopReason::Error(e) => writeln!(out, "{}", e)?,
+                }
+                writeln!(out, "{} instructions, {} cycles", summary.instructions, summary.cycles)?;
+                self.show_location(out)?;
+            }
+            Command::Break(addr) => {
+                self.cpu.add_breakpoint(addr);
+                writeln!(out, "breakpoint at ${:04X}", addr)?;
+            }
+            Command::Watch(addr) => {
+                self.cpu.add_watchpoint(addr);
+                writeln!(out, "watching ${:04X}", addr)?;
+            }
+            Command::Memory { addr, len } => self.hex_dump(addr, len, out)?,
+            Command::Disassemble { addr, count } => {
+                let start = addr.unwrap_or_else(|| self.cpu.program_counter());
+                let mut peek = Peek(&self.cpu.bus);
+                let lines = disasm::iter(&mut peek, start).variant(self.cpu.variant());
+                for line in lines.take(count) {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            Command::Registers => {
+                writeln!(out, "{:?}", self.cpu)?;
+                writeln!(out, "cycles: {}", self.cpu.cycles())?;
+            }
+            Command::Reset => {
+                self.cpu.reset();
+                self.show_location(out)?;
+            }
+            Command::Help => {
+                writeln!(out, "s step, sb step back, c continue, b ADDR break,")?;
+                writeln!(out, "w ADDR watch writes, m ADDR [LEN] memory,")?;
+                writeln!(out, "d [ADDR] [N] disassemble, r registers, reset, q quit")?;
+            }
+            Command::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // the instruction about to run, with the registers
+    pub fn show_location(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", trace_human(&self.cpu))
+    }
+
+    // 16 bytes a row, ?? where the bus can't be peeked without side effects
+    fn hex_dump(&self, addr: u16, len: usize, out: &mut dyn Write) -> io::Result<()> {
+        let end = (addr as usize + len).min(0x10000);
+        for row in (addr as usize..end).step_by(16) {
+            write!(out, "{:04X}:", row)?;
+            for a in row..(row + 16).min(end) {
+                match self.cpu.bus.peek(a as u16) {
+                    Some(value) => write!(out, " {:02X}", value)?,
+                    None => write!(out, " ??")?,
+                }
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::RomBuilder;
+    use crate::cartridge::Rom;
+
+    #[test]
+    fn test_parse() {
+        let parsed = [
+            ("s", Command::Step),
+            ("sb", Command::StepBack),
+            ("  c  ", Command::Continue),
+            ("b $C005", Command::Break(0xc005)),
+            ("b c005", Command::Break(0xc005)),
+            ("w 10", Command::Watch(0x0010)),
+            ("m $0200", Command::Memory { addr: 0x0200, len: 16 }),
+            ("m 0200 $20", Command::Memory { addr: 0x0200, len: 32 }),
+            ("d", Command::Disassemble { addr: None, count: 10 }),
+            ("d $c000 3", Command::Disassemble { addr: Some(0xc000), count: 3 }),
+            ("r", Command::Registers),
+            ("reset", Command::Reset),
+            ("q", Command::Quit),
+        ];
+        for (line, command) in parsed.iter() {
+            assert_eq!(Command::parse(line), Ok(*command), "{}", line);
+        }
+
+        let bad = [
+            ("x", "unknown command: x, h for help"),
+            ("b", "b needs an address"),
+            ("b $", "bad address: $"),
+            ("b 10000", "bad address: 10000"),
+            ("w $zz", "bad address: $zz"),
+            ("m 0200 ten", "bad count: ten"),
+            ("s 2", "too many arguments to s"),
+            ("d 0 1 2", "too many arguments to d"),
+        ];
+        for (line, err) in bad.iter() {
+            assert_eq!(Command::parse(line), Err(err.to_string()));
+        }
+    }
+
+    // NMI on vblank, with a handler that counts them at $10
+    fn nmi_counter() -> Debugger {
+        let rom = RomBuilder::new()
+            .code(
+                0xc000,
+                &[
+                    0xa9, 0x80, //       LDA #$80
+                    0x8d, 0x00, 0x20, // STA $2000
+                    0x4c, 0x05, 0xc0, // loop: JMP loop
+                ],
+            )
+            .code(0xc100, &[0xe6, 0x10, 0x40]) // INC $10; RTI
+            .nmi_vector(0xc100)
+            .reset_vector(0xc000)
+            .build();
+        let mut cpu = CPU::new(Bus::new(Rom::new(&rom).unwrap()));
+        cpu.reset();
+        Debugger::new(cpu)
+    }
+
+    #[test]
+    fn test_watch_every_frame() {
+        let mut debugger = nmi_counter();
+        debugger.add_watch("nmis", |cpu| cpu.bus.cpu_ram()[0x10] as i64);
+        debugger.add_watch("pc", |cpu| cpu.program_counter() as i64);
+        for _ in 0..10 {
+            assert_eq!(debugger.run_frame(), RunExit::FrameDone);
+        }
+
+        // sampled as vblank starts, ahead of the NMI it raises
+        let expected: Vec<(u64, i64)> = (0..10).map(|n| (n + 1, n as i64)).collect();
+        assert_eq!(debugger.watch_series("nmis"), Some(&expected[..]));
+        assert!(debugger.watch_series("pc").unwrap().iter().all(|&(_, pc)| pc >= 0xc005));
+        assert_eq!(debugger.watch_series("lives"), None);
+
+        debugger.add_watch("nmis", |cpu| cpu.bus.cpu_ram()[0x10] as i64 * 2);
+        debugger.run_frame();
+        assert_eq!(debugger.watch_series("nmis"), Some(&[(11, 20)][..]));
+        debugger.remove_watch("pc");
+        assert_eq!(debugger.watch_series("pc"), None);
+    }
+
+    #[test]
+    fn test_watch_every_scanline() {
+        let mut debugger = nmi_counter();
+        debugger.set_watch_every(WatchEvery::Scanline);
+        debugger.add_watch("line", |cpu| cpu.get_ppu_info().1 as i64);
+        debugger.run_frame();
+        debugger.run_frame();
+
+        // the first frame runs from power on, the second from one vblank to the next; line 241
+        // starts vblank, so its sample already counts the frame it finished
+        let lines: Vec<i64> = (1..=241).chain(242..=261).chain(0..=241).collect();
+        let frames: Vec<u64> = [[0].repeat(240), [1].repeat(262), vec![2]].concat();
+        let series = debugger.watch_series("line").unwrap();
+        assert_eq!(series.iter().map(|&(_, line)| line).collect::<Vec<_>>(), lines);
+        assert_eq!(series.iter().map(|&(frame, _)| frame).collect::<Vec<_>>(), frames);
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let rom = RomBuilder::new()
+            .code(
+                0xc000,
+                &[
+                    0xa2, 0x00, //       LDX #$00
+                    0xe8, //             loop: INX
+                    0x86, 0x10, //       STX $10
+                    0xe0, 0x03, //       CPX #$03
+                    0xd0, 0xf9, //       BNE loop
+                    0x00, //             BRK
+                ],
+            )
+            .build();
+        let mut cpu = CPU::new(Bus::new(Rom::new(&rom).unwrap()));
+        cpu.reset();
+        let mut debugger = Debugger::new(cpu);
+        let script = "b $c005\nc\nw 10\nc\nm $10 2\nd c005 2\ns\ns\nsb\nbogus\nq\ns\n";
+        let mut out = vec![];
+        debugger.show_location(&mut out).unwrap();
+        for line in script.lines() {
+            match Command::parse(line) {
+                Ok(command) => {
+                    if !debugger.execute(command, &mut out).unwrap() {
+                        break;
+                    }
+                }
+                Err(e) => writeln!(out, "{}", e).unwrap(),
+            }
+        }
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "C000    LDX #$00           A:00 X:00 Y:00 P:24 SP:FA PC:C000",
+                "breakpoint at $C005",
+                "breakpoint at $C005, hit 1",
+                "3 instructions, 7 cycles",
+                "C005    CPX #$03           A:00 X:01 Y:00 P:24 SP:FA PC:C005",
+                "watching $0010",
+                "watchpoint: $0010 written at $C003",
+                "4 instructions, 10 cycles",
+                "C005    CPX #$03           A:00 X:02 Y:00 P:24 SP:FA PC:C005",
+                "0010: 02 00",
+                "C005  E0 03     CPX #$03",
+                "C007  D0 F9     BNE $C002",
+                // the breakpoint at $C005 does not hold `s` up
+                "C007    BNE $C002          A:00 X:02 Y:00 P:A4 SP:FA PC:C007",
+                "C002    INX                A:00 X:02 Y:00 P:A4 SP:FA PC:C002",
+                "C007    BNE $C002          A:00 X:02 Y:00 P:A4 SP:FA PC:C007",
+                "unknown command: bogus, h for help",
+            ]
+        );
+        assert_eq!(debugger.cpu.program_counter(), 0xc007);
+    }
+}
@@ -0,0 +1,349 @@
+use crate::cpu::{CpuVariant, Mem};
+use crate::opcodes::{self, OpCode};
+use crate::symbols::SymbolTable;
+
+// Static disassembly: operands are decoded from the instruction bytes only, no register or
+// memory state is involved, so indexed and indirect operands have no resolved address.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str, // ".byte" for undecodable data
+    pub operand: String,
+    pub target: Option<u16>, // destination of JMP/JSR/branches
+    pub official: bool,
+}
+
+impl DisasmLine {
+    pub fn size(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+}
+
+// Laid out like the first columns of nestest.log: C5F5  A2 00     LDX #$00
+impl std::fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let line = format!(
+            "{:04X}  {:8} {: >4} {}",
+            self.addr,
+            bytes.join(" "),
+            self.mnemonic,
+            self.operand
+        );
+        f.write_str(line.trim_end())
+    }
+}
+
+pub struct Disasm<'a> {
+    mem: &'a mut dyn Mem,
+    next: Option<u16>, // None once the end of the address space is reached
+    table: &'static [Option<&'static OpCode>; 256],
+    stop_on_invalid: bool,
+    symbols: Option<&'a SymbolTable>,
+}
+
+// `count` lines from `start`, fewer when the end of the address space comes first
+pub fn disassemble(mem: &mut dyn Mem, start: u16, count: usize) -> Vec<DisasmLine> {
+    iter(mem, start).take(count).collect()
+}
+
+// Mem reads take &mut self (bus reads have side effects), so the iterator borrows mutably
+pub fn iter(mem: &mut dyn Mem, start: u16) -> Disasm<'_> {
+    Disasm {
+        mem,
+        next: Some(start),
+        table: &opcodes::OPCODES_TABLE,
+        stop_on_invalid: false,
+        symbols: None,
+    }
+}
+
+impl<'a> Disasm<'a> {
+    pub fn variant(mut self, variant: CpuVariant) -> Self {
+        self.table = match variant {
+            CpuVariant::Nmos6502 => &opcodes::OPCODES_TABLE,
+            CpuVariant::Wdc65c02 => &opcodes::OPCODES_65C02_TABLE,
+        };
+        self
+    }
+
+    // by default an invalid opcode yields a one byte ".byte" line and decoding goes on
+    pub fn stop_on_invalid(mut self, stop: bool) -> Self {
+        self.stop_on_invalid = stop;
+        self
+    }
+
+    // labelled operands read `JSR init_ppu ; $C4A0`, see SymbolTable::annotate
+    pub fn symbols(mut self, symbols: &'a SymbolTable) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    fn data_byte(&mut self, addr: u16, code: u8) -> Option<DisasmLine> {
+        if self.stop_on_invalid {
+            self.next = None;
+            return None;
+        }
+        self.next = addr.checked_add(1);
+        Some(DisasmLine {
+            addr,
+            bytes: vec![code],
+            mnemonic: ".byte",
+            operand: format!("${:02X}", code),
+            target: None,
+            official: false,
+        })
+    }
+}
+
+impl<'a> Iterator for Disasm<'a> {
+    type Item = DisasmLine;
+
+    fn next(&mut self) -> Option<DisasmLine> {
+        let addr = self.next?;
+        let code = self.mem.mem_read(addr);
+
+        let op = match self.table[code as usize] {
+            // an instruction running past $FFFF is data as well
+            Some(op) if addr as usize + op.bytes as usize <= 0x10000 => op,
+            _ => return self.data_byte(addr, code),
+        };
+
+        let mut bytes = vec![code];
+        for i in 1..op.bytes as u16 {
+            bytes.push(self.mem.mem_read(addr + i));
+        }
+        let mut operand = op.format_operand(&bytes[1..], addr);
+        if let Some(annotated) = self.symbols.and_then(|symbols| symbols.annotate(&operand)) {
+            operand = annotated;
+        }
+        let target = op.target(&bytes[1..], addr);
+
+        self.next = addr.checked_add(op.bytes as u16);
+        Some(DisasmLine {
+            addr,
+            bytes,
+            mnemonic: op.mnemonic,
+            operand,
+            target,
+            official: op.official,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    fn bus_with(addr: u16, program: &[u8]) -> Bus {
+        let mut bus = Bus::new(test_rom());
+        for (i, byte) in program.iter().enumerate() {
+            bus.mem_write(addr + i as u16, *byte);
+        }
+        bus
+    }
+
+    #[test]
+    fn test_take_five() {
+        let mut bus = bus_with(
+            0x0600,
+            &[
+                0xa2, 0x08, //       LDX #$08
+                0xbd, 0x00, 0x02, // LDA $0200,X
+                0x0a, //             ASL A
+                0xca, //             DEX
+                0xd0, 0xf9, //       BNE $0602
+                0x20, 0x00, 0x07, // JSR $0700 (not taken by take(5))
+            ],
+        );
+
+        let lines: Vec<DisasmLine> = iter(&mut bus, 0x0600).take(5).collect();
+
+        let addrs: Vec<u16> = lines.iter().map(|l| l.addr).collect();
+        let sizes: Vec<u16> = lines.iter().map(|l| l.size()).collect();
+        assert_eq!(addrs, vec![0x0600, 0x0602, 0x0605, 0x0606, 0x0607]);
+        assert_eq!(sizes, vec![2, 3, 1, 1, 2]);
+
+        assert_eq!(lines[1].mnemonic, "LDA");
+        assert_eq!(lines[1].operand, "$0200,X");
+        assert_eq!(lines[2].operand, "A");
+        assert_eq!(lines[4].operand, "$0602");
+        assert_eq!(lines[4].target, Some(0x0602));
+        assert_eq!(lines[0].target, None);
+    }
+
+    #[test]
+    fn test_control_flow_targets() {
+        let mut bus = bus_with(
+            0x0600,
+            &[0x20, 0x34, 0x12, 0x4c, 0x00, 0x06, 0x6c, 0xfe, 0x02, 0x60],
+        );
+        let lines: Vec<DisasmLine> = iter(&mut bus, 0x0600).take(4).collect();
+        assert_eq!(lines[0].target, Some(0x1234));
+        assert_eq!(lines[1].target, Some(0x0600));
+        assert_eq!(lines[2].operand, "($02FE)");
+        assert_eq!(lines[2].target, None);
+        assert_eq!(lines[3].mnemonic, "RTS");
+    }
+
+    #[test]
+    fn test_invalid_opcode_yields_byte_or_stops() {
+        // NOP; *LAX $10 (not a 65C02 instruction), which then decodes as BPL $0614
+        let program = [0xea, 0xa7, 0x10, 0x10];
+
+        let mut bus = bus_with(0x0600, &program);
+        let lines: Vec<DisasmLine> = iter(&mut bus, 0x0600)
+            .variant(CpuVariant::Wdc65c02)
+            .take(4)
+            .collect();
+        let mnemonics: Vec<&str> = lines.iter().map(|l| l.mnemonic).collect();
+        assert_eq!(mnemonics, vec!["NOP", ".byte", "BPL", "BRK"]);
+        assert_eq!(lines[1].operand, "$A7");
+
+        let lines: Vec<DisasmLine> = iter(&mut bus, 0x0600)
+            .variant(CpuVariant::Wdc65c02)
+            .stop_on_invalid(true)
+            .collect();
+        assert_eq!(lines.len(), 1);
+
+        // the NMOS table decodes every byte
+        let lines: Vec<DisasmLine> = iter(&mut bus, 0x0600).take(2).collect();
+        assert_eq!(lines[1].mnemonic, "*LAX");
+    }
+
+    struct FlatMemory(Vec<u8>);
+
+    impl Mem for FlatMemory {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            self.0[addr as usize]
+        }
+
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.0[addr as usize] = data;
+        }
+    }
+
+    #[test]
+    fn test_every_addressing_mode() {
+        let mut bus = bus_with(
+            0x0600,
+            &[
+                0xa9, 0x10, //       LDA #$10
+                0xa5, 0x10, //       LDA $10
+                0xb5, 0x10, //       LDA $10,X
+                0xb6, 0x10, //       LDX $10,Y
+                0xad, 0x34, 0x12, // LDA $1234
+                0xbd, 0x34, 0x12, // LDA $1234,X
+                0xb9, 0x34, 0x12, // LDA $1234,Y
+                0xa1, 0x10, //       LDA ($10,X)
+                0xb1, 0x10, //       LDA ($10),Y
+                0x6c, 0xff, 0x02, // JMP ($02FF)
+                0x4c, 0x00, 0x06, // JMP $0600
+                0x20, 0x00, 0x07, // JSR $0700
+                0xf0, 0xde, //       BEQ $0600
+                0x4a, //             LSR A
+                0x18, //             CLC
+                0x04, 0x10, //       *NOP $10
+                0x02, //             *JAM
+            ],
+        );
+        let lines = disassemble(&mut bus, 0x0600, 17);
+        let text: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "0600  A9 10     LDA #$10",
+                "0602  A5 10     LDA $10",
+                "0604  B5 10     LDA $10,X",
+                "0606  B6 10     LDX $10,Y",
+                "0608  AD 34 12  LDA $1234",
+                "060B  BD 34 12  LDA $1234,X",
+                "060E  B9 34 12  LDA $1234,Y",
+                "0611  A1 10     LDA ($10,X)",
+                "0613  B1 10     LDA ($10),Y",
+                "0615  6C FF 02  JMP ($02FF)",
+                "0618  4C 00 06  JMP $0600",
+                "061B  20 00 07  JSR $0700",
+                "061E  F0 DE     BEQ $05FE",
+                "0620  4A        LSR A",
+                "0621  18        CLC",
+                "0622  04 10    *NOP $10",
+                "0624  02       *JAM",
+            ]
+        );
+        let unofficial: Vec<u16> = lines.iter().filter(|l| !l.official).map(|l| l.addr).collect();
+        assert_eq!(unofficial, vec![0x0622, 0x0624]);
+
+        // (zp) only exists on the 65C02
+        let mut bus = bus_with(0x0600, &[0xb2, 0x10]);
+        let line = iter(&mut bus, 0x0600).variant(CpuVariant::Wdc65c02).next().unwrap();
+        assert_eq!(line.to_string(), "0600  B2 10     LDA ($10)");
+    }
+
+    #[test]
+    fn test_labelled_operands() {
+        let symbols = SymbolTable::from_nl(
+            "$0600#main#\n$C4A0#init_ppu#\n$0010#ptr#\n$0200/100#oam_buf#sprite copy",
+        )
+        .unwrap();
+        let mut bus = bus_with(
+            0x0600,
+            &[
+                0x20, 0xa0, 0xc4, // JSR $C4A0
+                0xb1, 0x10, //       LDA ($10),Y
+                0x9d, 0x00, 0x02, // STA $0200,X
+                0xa9, 0x10, //       LDA #$10
+                0xd0, 0xf4, //       BNE $0600
+                0x4c, 0x34, 0x12, // JMP $1234
+            ],
+        );
+        let text: Vec<String> = iter(&mut bus, 0x0600)
+            .symbols(&symbols)
+            .take(6)
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(
+            text,
+            vec![
+                "0600  20 A0 C4  JSR init_ppu ; $C4A0",
+                "0603  B1 10     LDA (ptr),Y ; $10",
+                "0605  9D 00 02  STA oam_buf,X ; $0200",
+                "0608  A9 10     LDA #$10",
+                "060A  D0 F4     BNE main ; $0600",
+                "060C  4C 34 12  JMP $1234",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_branch_targets_wrap_around_the_address_space() {
+        let mut mem = FlatMemory(vec![0xea; 0x10000]);
+        mem.mem_write(0xfffd, 0xd0); // BNE +$10, from $FFFF
+        mem.mem_write(0xfffe, 0x10);
+        mem.mem_write(0x0002, 0x10); // BPL -$10, from $0004
+        mem.mem_write(0x0003, 0xf0);
+
+        let line = &disassemble(&mut mem, 0xfffd, 1)[0];
+        assert_eq!((line.operand.as_str(), line.target), ("$000F", Some(0x000f)));
+        let line = &disassemble(&mut mem, 0x0002, 1)[0];
+        assert_eq!((line.operand.as_str(), line.target), ("$FFF4", Some(0xfff4)));
+
+        // the listing itself ends with the address space
+        assert_eq!(disassemble(&mut mem, 0xfffd, 5).len(), 2);
+    }
+
+    #[test]
+    fn test_stops_at_end_of_address_space() {
+        let mut mem = FlatMemory(vec![0xea; 0x10000]);
+        mem.mem_write(0xfffe, 0xad); // LDA absolute would run past $FFFF
+
+        let lines: Vec<DisasmLine> = iter(&mut mem, 0xfffd).collect();
+        let mnemonics: Vec<&str> = lines.iter().map(|l| l.mnemonic).collect();
+        assert_eq!(mnemonics, vec!["NOP", ".byte", "NOP"]);
+        assert_eq!(lines[2].addr, 0xffff);
+    }
+}
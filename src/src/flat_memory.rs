@@ -0,0 +1,136 @@
+use crate::cpu::{CpuBus, Mem};
+
+const ADDRESS_SPACE: usize = 0x10000;
+
+// 64 KiB of RAM and nothing else: no mirrors, no ROM, no devices. For running the CPU on its
+// own, in unit tests and with test suites assembled for a plain 6502.
+#[derive(Clone)]
+pub struct FlatMemory {
+    data: Box<[u8; ADDRESS_SPACE]>,
+    cycles: usize,
+}
+
+impl FlatMemory {
+    pub fn new() -> Self {
+        FlatMemory {
+            data: Box::new([0; ADDRESS_SPACE]),
+            cycles: 0,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..]
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        FlatMemory::new()
+    }
+}
+
+impl Mem for FlatMemory {
+    #[inline]
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.data[addr as usize]
+    }
+
+    #[inline]
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.data[addr as usize] = data;
+    }
+}
+
+impl CpuBus for FlatMemory {
+    fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles;
+    }
+
+    fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    fn stack_page(&self) -> &[u8] {
+        &self.data[0x0100..0x0200]
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+        Some(self.data[addr as usize])
+    }
+
+    fn stored_byte(&self, addr: u16) -> Option<u8> {
+        Some(self.data[addr as usize])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{BrkBehavior, RunExit, CPU};
+    use crate::disasm;
+    use std::{env, fs};
+
+    #[test]
+    fn test_cpu_on_flat_memory() {
+        let mut cpu = CPU::with_bus(FlatMemory::new());
+        // LDX #$03; loop: TXA; STA $F000,X; DEX; BPL loop; BRK
+        cpu.load_at(0x8000, &[0xa2, 0x03, 0x8a, 0x9d, 0x00, 0xf0, 0xca, 0x10, 0xf9, 0x00]).unwrap();
+        cpu.set_reset_vector(0x8000);
+        cpu.power_on();
+        assert_eq!(cpu.run_until(|_| false), RunExit::Brk);
+        // no ROM at $8000 and no mirroring of RAM
+        assert_eq!(&cpu.bus.data()[0xf000..0xf004], &[0, 1, 2, 3]);
+        assert_eq!(cpu.bus.data()[0x0000], 0);
+    }
+
+    // Klaus Dormann's 6502_functional_test.bin, which is not in the repo: point
+    // KLAUS_FUNCTIONAL_TEST at it and run with --ignored; without it the test is skipped. Until
+    // the CPU does BCD it has to be assembled with disable_decimal = 1. Failures end in a JMP or
+    // branch to itself; success is the same kind of loop at $3469.
+    #[test]
+    #[ignore]
+    fn test_klaus_functional() {
+        const SUCCESS: u16 = 0x3469;
+        const CYCLE_CAP: u64 = 200_000_000;
+        let path = env::var("KLAUS_FUNCTIONAL_TEST")
+            .unwrap_or_else(|_| "6502_functional_test.bin".to_string());
+        let image = match fs::read(&path) {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("skipped, {}: {}", path, e);
+                return;
+            }
+        };
+
+        let mut cpu = CPU::with_bus(FlatMemory::new());
+        cpu.set_brk_behavior(BrkBehavior::Vector);
+        cpu.load_at(0x0000, &image).unwrap();
+        cpu.set_program_counter(0x0400);
+        let mut last_pc = None;
+        let exit = cpu.run_until(|cpu| {
+            let pc = Some(cpu.program_counter());
+            let trapped = pc == std::mem::replace(&mut last_pc, pc);
+            trapped || cpu.cycles() >= CYCLE_CAP
+        });
+
+        let pc = cpu.program_counter();
+        if exit != RunExit::PredicateHit || pc != SUCCESS {
+            let listing: Vec<String> = disasm::iter(&mut cpu, pc)
+                .take(4)
+                .map(|line| format!("{:04X}  {} {}", line.addr, line.mnemonic, line.operand))
+                .collect();
+            panic!(
+                "{:?} at {:04X} after {} cycles, A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}\n{}",
+                exit,
+                pc,
+                cpu.cycles(),
+                cpu.register_a(),
+                cpu.register_x(),
+                cpu.register_y(),
+                cpu.status(),
+                cpu.stack_pointer(),
+                listing.join("\n")
+            );
+        }
+    }
+}
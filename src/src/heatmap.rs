@@ -0,0 +1,106 @@
+use std::cmp::Reverse;
+
+const ADDRESS_SPACE: usize = 0x10000;
+
+// Reads and writes of one address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeatmapEntry {
+    pub addr: u16,
+    pub reads: u32,
+    pub writes: u32,
+}
+
+impl HeatmapEntry {
+    pub fn total(&self) -> u32 {
+        self.reads.saturating_add(self.writes)
+    }
+}
+
+// How many times each CPU address was read and written. Counters stop at u32::MAX instead of
+// wrapping, so a busy address never looks cold.
+#[derive(Clone)]
+pub struct Heatmap {
+    reads: Box<[u32]>,
+    writes: Box<[u32]>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Heatmap {
+            reads: vec![0; ADDRESS_SPACE].into_boxed_slice(),
+            writes: vec![0; ADDRESS_SPACE].into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    pub fn record_read(&mut self, addr: u16) {
+        let count = &mut self.reads[addr as usize];
+        *count = count.saturating_add(1);
+    }
+
+    #[inline]
+    pub fn record_write(&mut self, addr: u16) {
+        let count = &mut self.writes[addr as usize];
+        *count = count.saturating_add(1);
+    }
+
+    pub fn reads(&self, addr: u16) -> u32 {
+        self.reads[addr as usize]
+    }
+
+    pub fn writes(&self, addr: u16) -> u32 {
+        self.writes[addr as usize]
+    }
+
+    pub fn entry(&self, addr: u16) -> HeatmapEntry {
+        HeatmapEntry { addr, reads: self.reads(addr), writes: self.writes(addr) }
+    }
+
+    // The `count` addresses with the most reads and writes together, hottest first, lower
+    // addresses first on a tie. Addresses never touched are left out.
+    pub fn hottest(&self, count: usize) -> Vec<HeatmapEntry> {
+        let mut entries: Vec<HeatmapEntry> = (0..ADDRESS_SPACE)
+            .map(|addr| self.entry(addr as u16))
+            .filter(|entry| entry.total() > 0)
+            .collect();
+        entries.sort_by_key(|entry| (Reverse(entry.total()), entry.addr));
+        entries.truncate(count);
+        entries
+    }
+
+    pub fn clear(&mut self) {
+        self.reads.iter_mut().for_each(|count| *count = 0);
+        self.writes.iter_mut().for_each(|count| *count = 0);
+    }
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Heatmap::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_counters_saturate() {
+        let mut heatmap = Heatmap::new();
+        heatmap.reads[0x10] = u32::MAX - 1;
+        heatmap.record_read(0x10);
+        heatmap.record_read(0x10);
+        heatmap.record_write(0x10);
+        assert_eq!(heatmap.reads(0x10), u32::MAX);
+        assert_eq!(heatmap.entry(0x10).total(), u32::MAX);
+
+        heatmap.record_write(0x20);
+        heatmap.record_read(0x08);
+        let hottest: Vec<u16> = heatmap.hottest(10).iter().map(|e| e.addr).collect();
+        assert_eq!(hottest, vec![0x10, 0x08, 0x20]);
+        assert_eq!(heatmap.hottest(1).len(), 1);
+
+        heatmap.clear();
+        assert!(heatmap.hottest(10).is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+
+pub const DEFAULT_HISTORY_LEN: usize = 4096;
+
+// One instruction as it was fetched, registers as they were before it ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HistoryEntry {
+    pub pc: u16,
+    pub opcode: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}  {:02X}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            self.pc, self.opcode, self.a, self.x, self.y, self.p, self.sp
+        )
+    }
+}
+
+// The last instructions the CPU ran, for finding out how a program got where it crashed.
+// The buffer is allocated once; recording overwrites the oldest entry.
+#[derive(Clone)]
+pub struct PcHistory {
+    entries: Box<[HistoryEntry]>,
+    next: usize, // slot the next entry goes to
+    len: usize,
+}
+
+impl PcHistory {
+    // capacity is at least 1
+    pub fn new(capacity: usize) -> Self {
+        PcHistory {
+            entries: vec![HistoryEntry::default(); capacity.max(1)].into_boxed_slice(),
+            next: 0,
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, entry: HistoryEntry) {
+        self.entries[self.next] = entry;
+        self.next = (self.next + 1) % self.entries.len();
+        self.len = (self.len + 1).min(self.entries.len());
+    }
+
+    // oldest first
+    pub fn iter(&self) -> impl Iterator<Item = HistoryEntry> + '_ {
+        let start = (self.next + self.entries.len() - self.len) % self.entries.len();
+        (0..self.len).map(move |i| self.entries[(start + i) % self.entries.len()])
+    }
+
+    // the `count` most recent entries, oldest first
+    pub fn last(&self, count: usize) -> Vec<HistoryEntry> {
+        self.iter().skip(self.len.saturating_sub(count)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.next = 0;
+        self.len = 0;
+    }
+}
+
+impl Default for PcHistory {
+    fn default() -> Self {
+        PcHistory::new(DEFAULT_HISTORY_LEN)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(pc: u16) -> HistoryEntry {
+        HistoryEntry { pc, ..Default::default() }
+    }
+
+    #[test]
+    fn test_wraps_keeping_the_newest() {
+        let mut history = PcHistory::new(3);
+        assert!(history.is_empty());
+        for pc in 0..5 {
+            history.record(at(pc));
+        }
+        let pcs: Vec<u16> = history.iter().map(|e| e.pc).collect();
+        assert_eq!(pcs, vec![2, 3, 4]);
+        assert_eq!(history.last(2), vec![at(3), at(4)]);
+        assert_eq!(history.last(10).len(), 3);
+
+        history.clear();
+        history.record(at(7));
+        assert_eq!(history.last(10), vec![at(7)]);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::cpu::StepInfo;
+use std::collections::VecDeque;
+
+pub const DEFAULT_JOURNAL_LEN: usize = 100_000;
+
+// The CPU state a step started from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavedState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    pub pc: u16,
+    pub cycles: u64,
+    pub irq_mask_delayed: Option<bool>,
+    pub jammed: bool,
+    pub current: StepInfo,
+}
+
+#[derive(Clone)]
+struct Entry {
+    state: SavedState,
+    writes: usize, // how many of the last bytes in Journal::writes belong to this step
+    reversible: bool,
+}
+
+// One step undone: the state to put back, and the bytes to put back, the last written first
+pub struct Undo {
+    pub state: SavedState,
+    pub writes: Vec<(u16, u8)>,
+    pub reversible: bool,
+}
+
+// The last steps the CPU took, each with what it takes to undo it: the registers before it and
+// the old value of every byte it wrote. Old bytes of all steps share one buffer, so recording
+// allocates nothing once the journal is warm. A full journal drops its oldest step.
+#[derive(Clone)]
+pub struct Journal {
+    entries: VecDeque<Entry>,
+    writes: VecDeque<(u16, u8)>,
+    capacity: usize,
+    recording: bool, // between begin() and end(), accesses belong to the last step
+}
+
+impl Journal {
+    // capacity is at least 1
+    pub fn new(capacity: usize) -> Self {
+        Journal {
+            entries: VecDeque::new(),
+            writes: VecDeque::new(),
+            capacity: capacity.max(1),
+            recording: false,
+        }
+    }
+
+    pub fn begin(&mut self, state: SavedState) {
+        if self.entries.len() == self.capacity {
+            if let Some(oldest) = self.entries.pop_front() {
+                self.writes.drain(..oldest.writes);
+            }
+        }
+        self.entries.push_back(Entry { state, writes: 0, reversible: true });
+        self.recording = true;
+    }
+
+    pub fn end(&mut self) {
+        self.recording = false;
+    }
+
+    // drops the step begun last, for one that failed before changing anything
+    pub fn discard(&mut self) {
+        if self.recording {
+            self.pop();
+            self.recording = false;
+        }
+    }
+
+    #[inline]
+    pub fn record_write(&mut self, addr: u16, old: u8) {
+        if let (true, Some(entry)) = (self.recording, self.entries.back_mut()) {
+            entry.writes += 1;
+            self.writes.push_back((addr, old));
+        }
+    }
+
+    // the step did something writing old bytes back won't undo, like touching a register
+    #[inline]
+    pub fn mark_irreversible(&mut self) {
+        if let (true, Some(entry)) = (self.recording, self.entries.back_mut()) {
+            entry.reversible = false;
+        }
+    }
+
+    // the last step, ending it if it was still being recorded
+    pub fn pop(&mut self) -> Option<Undo> {
+        let entry = self.entries.pop_back()?;
+        self.recording = false;
+        let start = self.writes.len() - entry.writes;
+        let writes = self.writes.drain(start..).rev().collect();
+        Some(Undo { state: entry.state, writes, reversible: entry.reversible })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.writes.clear();
+        self.recording = false;
+    }
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Journal::new(DEFAULT_JOURNAL_LEN)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(pc: u16) -> SavedState {
+        SavedState {
+            a: 0,
+            x: 0,
+            y: 0,
+            p: 0,
+            sp: 0,
+            pc,
+            cycles: 0,
+            irq_mask_delayed: None,
+            jammed: false,
+            current: StepInfo {
+                pc,
+                opcode: 0xea,
+                mnemonic: "NOP",
+                mode: crate::cpu::AddressingMode::NoneAddressing,
+                bytes: 1,
+                cycles: 2,
+            },
+        }
+    }
+
+    #[test]
+    fn test_full_journal_drops_the_oldest_step_and_its_writes() {
+        let mut journal = Journal::new(2);
+        for pc in 0..3 {
+            journal.begin(at(pc));
+            journal.record_write(0x10, pc as u8);
+            journal.record_write(0x11, pc as u8);
+            journal.end();
+        }
+        // outside a step nothing is recorded
+        journal.record_write(0x12, 0xff);
+        journal.mark_irreversible();
+        assert_eq!(journal.len(), 2);
+
+        let undo = journal.pop().unwrap();
+        assert_eq!((undo.state.pc, undo.reversible), (2, true));
+        assert_eq!(undo.writes, vec![(0x11, 2), (0x10, 2)]);
+        assert_eq!(journal.pop().unwrap().writes, vec![(0x11, 1), (0x10, 1)]);
+        assert!(journal.pop().is_none());
+        assert!(journal.writes.is_empty());
+    }
+}
@@ -0,0 +1,29 @@
+pub mod bus;
+pub mod call_stack;
+pub mod cartridge;
+pub mod cdl;
+pub mod clock;
+pub mod coverage;
+pub mod cpu;
+pub mod cpu_builder;
+pub mod debugger;
+pub mod disasm;
+pub mod flat_memory;
+pub mod heatmap;
+pub mod history;
+pub mod journal;
+#[cfg(test)]
+mod nestest;
+pub mod mapper;
+pub mod opcodes;
+pub mod trace;
+pub mod ppu;
+pub mod ppu_registers;
+pub mod ram_search;
+pub mod symbols;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
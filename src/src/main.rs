@@ -1,18 +1,10 @@
-pub mod bus;
-pub mod cartridge;
-pub mod cpu;
-pub mod opcodes;
-pub mod trace;
-pub mod ppu;
-pub mod ppu_registers;
-
-use bus::Bus;
-use cartridge::Rom;
-use cpu::Mem;
-use cpu::CPU;
-use trace::trace;
-use ppu::PPU;
-// use rand::Rng;
+use nes_emu::bus::Bus;
+use nes_emu::cartridge::Rom;
+use nes_emu::cpu::Mem;
+use nes_emu::cpu::CPU;
+use nes_emu::trace::trace;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
@@ -20,14 +12,10 @@ use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
 use std::env;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
 // use std::time::Duration;
 
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate bitflags;
-
 fn color(byte: u8) -> Color {
     match byte {
         0 => sdl2::pixels::Color::BLACK,
@@ -59,7 +47,7 @@ fn read_screen_state(cpu: &mut CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     update
 }
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+fn handle_user_input(last_key: &AtomicU8, event_pump: &mut EventPump) {
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
@@ -71,32 +59,34 @@ fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
                 keycode: Some(Keycode::W),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x77);
+                last_key.store(0x77, Ordering::Relaxed);
             }
             Event::KeyDown {
                 keycode: Some(Keycode::S),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x73);
+                last_key.store(0x73, Ordering::Relaxed);
             }
             Event::KeyDown {
                 keycode: Some(Keycode::A),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x61);
+                last_key.store(0x61, Ordering::Relaxed);
             }
             Event::KeyDown {
                 keycode: Some(Keycode::D),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x64);
+                last_key.store(0x64, Ordering::Relaxed);
             }
             _ => { /* do nothing */ }
         }
     }
 }
 
-fn main() {
+// The tutorial snake game (dump/snake.nes): it reads a random byte from $FE and the last
+// key pressed from $FF, and draws to a 32x32 screen at $0200-$05FF
+fn run_snake(mut cpu: CPU) {
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -115,6 +105,34 @@ fn main() {
         .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
         .unwrap();
 
+    let mut rng = StdRng::from_entropy();
+    cpu.bus.map_read(0xfe, move || rng.gen_range(1, 16));
+    let last_key = Arc::new(AtomicU8::new(0));
+    let key = Arc::clone(&last_key);
+    cpu.bus.map_read(0xff, move || key.load(Ordering::Relaxed));
+
+    let mut screen_state = [0u8; 32 * 3 * 32];
+    cpu.run_with_callback(move |cpu| {
+        handle_user_input(&last_key, &mut event_pump);
+
+        if read_screen_state(cpu, &mut screen_state) {
+            texture.update(None, &screen_state, 32 * 3).unwrap();
+
+            canvas.copy(&texture, None, None).unwrap();
+
+            canvas.present();
+        }
+
+        ::std::thread::sleep(std::time::Duration::new(0, 70_000));
+    })
+    .into_result()
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+}
+
+fn main() {
     //load the game
     let args: Vec<String> = env::args().collect();
     let mut file_path = "dump/".to_owned();
@@ -128,26 +146,20 @@ fn main() {
 
     let bus = Bus::new(rom);
     let mut cpu = CPU::new(bus);
-    cpu.reset();
-    cpu.program_counter = 0xC000;
-    // let mut screen_state = [0 as u8; 32 * 3 * 32];
-    // let mut rng = rand::thread_rng();
+    cpu.power_on();
+    if args[1] == "snake" {
+        run_snake(cpu);
+        return;
+    }
+    cpu.set_program_counter(0xC000);
 
     // run the game cycle
     cpu.run_with_callback(move |cpu| {
         println!("{}", trace(cpu));
-    // handle_user_input(cpu, &mut event_pump);
-
-    // cpu.mem_write(0xfe, rng.gen_range(1, 16));
-
-    // if read_screen_state(cpu, &mut screen_state) {
-    //     texture.update(None, &screen_state, 32 * 3).unwrap();
-
-    //     canvas.copy(&texture, None, None).unwrap();
-
-    //     canvas.present();
-    // }
-
-    // ::std::thread::sleep(std::time::Duration::new(0, 70_000));
+    })
+    .into_result()
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
     });
 }
@@ -0,0 +1,481 @@
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+
+// CPU side of a cartridge: work RAM at $6000-$7FFF and PRG-ROM at $8000-$FFFF. CHR still
+// lives in the PPU, so CHR banking and mapper-controlled mirroring are not emulated yet.
+// Send so a Bus, and the whole machine with it, can be moved onto an emulation thread.
+pub trait Mapper: Send {
+    // `open_bus` is what the CPU sees where nothing drives the bus. A mapper whose reads have
+    // side effects overrides this, plain ones just peek.
+    fn read_prg(&mut self, addr: u16, open_bus: u8) -> u8 {
+        self.peek_prg(addr).unwrap_or(open_bus)
+    }
+
+    // what a read of `addr` would return, without side effects; None where nothing drives the
+    // bus, like disabled work RAM
+    fn peek_prg(&self, addr: u16) -> Option<u8>;
+
+    fn write_prg(&mut self, addr: u16, data: u8);
+
+    // overwrites the byte currently mapped at `addr` ($6000-$FFFF), for loaders and debuggers;
+    // unlike write_prg it never reaches the mapper registers, and it stores into work RAM
+    // whatever its enable and write-protect switches say
+    fn patch_prg(&mut self, addr: u16, data: u8);
+
+    fn prg_ram(&self) -> &[u8];
+
+    // offset into PRG-ROM of the byte currently mapped at `addr`; None outside $8000-$FFFF
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize>;
+
+    fn prg_rom_len(&self) -> usize;
+
+    // lets Bus, which holds a Box<dyn Mapper>, be cloned
+    fn box_clone(&self) -> Box<dyn Mapper>;
+}
+
+impl Clone for Box<dyn Mapper> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// Unknown mapper numbers fall back to NROM, which is how every ROM was treated before
+pub fn new(mapper: u8, prg_rom: Vec<u8>, prg_ram_size: usize) -> Box<dyn Mapper> {
+    let ram = WorkRam::new(prg_ram_size);
+    match mapper {
+        1 => Box::new(Mmc1::new(prg_rom, ram)),
+        4 => Box::new(Mmc3::new(prg_rom, ram)),
+        _ => Box::new(Nrom { prg_rom, ram }),
+    }
+}
+
+// Work RAM with the enable and write-protect switches MMC1 and MMC3 put in front of it.
+// Games probe it to detect the RAM, so a disabled chip must read as open bus.
+#[derive(Clone)]
+struct WorkRam {
+    data: Vec<u8>,
+    enabled: bool,
+    write_protected: bool,
+}
+
+impl WorkRam {
+    fn new(size: usize) -> Self {
+        WorkRam {
+            data: vec![0; size],
+            enabled: true,
+            write_protected: false,
+        }
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+        if !self.enabled || self.data.is_empty() {
+            return None;
+        }
+        Some(self.data[(addr - PRG_RAM) as usize % self.data.len()])
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if self.enabled && !self.write_protected && !self.data.is_empty() {
+            let len = self.data.len();
+            self.data[(addr - PRG_RAM) as usize % len] = data;
+        }
+    }
+
+    fn patch(&mut self, addr: u16, data: u8) {
+        if !self.data.is_empty() {
+            let len = self.data.len();
+            self.data[(addr - PRG_RAM) as usize % len] = data;
+        }
+    }
+}
+
+// offset of byte `addr` of 16 KiB (or 8 KiB) bank `bank`, wrapping around the ROM size
+fn banked(prg_rom: &[u8], bank_size: usize, bank: usize, addr: u16) -> usize {
+    let banks = prg_rom.len() / bank_size;
+    let offset = addr as usize % bank_size;
+    (bank % banks) * bank_size + offset
+}
+
+// Mapper 0: 16 or 32 KiB of PRG, a 16 KiB image is mirrored at $C000
+#[derive(Clone)]
+struct Nrom {
+    prg_rom: Vec<u8>,
+    ram: WorkRam,
+}
+
+impl Mapper for Nrom {
+    fn peek_prg(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.ram.peek(addr),
+            PRG_ROM..=0xFFFF => Some(self.prg_rom[(addr - PRG_ROM) as usize % self.prg_rom.len()]),
+            _ => None,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.ram.write(addr, data),
+            PRG_ROM..=0xFFFF => panic!("Attempt to write to Cartridge ROM space: {:x}", addr),
+            _ => {}
+        }
+    }
+
+    fn patch_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.ram.patch(addr, data),
+            PRG_ROM..=0xFFFF => {
+                let len = self.prg_rom.len();
+                self.prg_rom[(addr - PRG_ROM) as usize % len] = data;
+            }
+            _ => {}
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.ram.data
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            PRG_ROM..=0xFFFF => Some((addr - PRG_ROM) as usize % self.prg_rom.len()),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+// Mapper 1. Registers are loaded serially, one bit per write to $8000-$FFFF; the fifth write
+// picks the register from address bits 13-14. Bit 4 of the PRG bank register ($E000)
+// disables work RAM.
+#[derive(Clone)]
+struct Mmc1 {
+    prg_rom: Vec<u8>,
+    ram: WorkRam,
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    fn new(prg_rom: Vec<u8>, ram: WorkRam) -> Self {
+        Mmc1 {
+            prg_rom,
+            ram,
+            shift: 0,
+            shift_count: 0,
+            control: 0x0c, // power-up state: last bank fixed at $C000
+            prg_bank: 0,
+        }
+    }
+
+    fn rom_offset(&self, addr: u16) -> usize {
+        const BANK: usize = 0x4000;
+        let bank = self.prg_bank as usize;
+        let last = self.prg_rom.len() / BANK - 1;
+        let high = addr >= 0xC000;
+        let bank = match ((self.control >> 2) & 0b11, high) {
+            // 32 KiB mode ignores the low bit of the bank number
+            (0, _) | (1, _) => (bank & !1) + high as usize,
+            (2, false) => 0,
+            (2, true) => bank,
+            (_, false) => bank,
+            (_, true) => last,
+        };
+        banked(&self.prg_rom, BANK, bank, addr)
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xDFFF => {} // CHR banks
+            _ => {
+                self.prg_bank = value & 0x0f;
+                self.ram.enabled = value & 0x10 == 0;
+            }
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn peek_prg(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.ram.peek(addr),
+            PRG_ROM..=0xFFFF => Some(self.prg_rom[self.rom_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.ram.write(addr, data),
+            PRG_ROM..=0xFFFF => {
+                if data & 0x80 != 0 {
+                    self.shift = 0;
+                    self.shift_count = 0;
+                    self.control |= 0x0c;
+                    return;
+                }
+                self.shift |= (data & 1) << self.shift_count;
+                self.shift_count += 1;
+                if self.shift_count == 5 {
+                    let value = self.shift;
+                    self.shift = 0;
+                    self.shift_count = 0;
+                    self.write_register(addr, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn patch_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.ram.patch(addr, data),
+            PRG_ROM..=0xFFFF => {
+                let offset = self.rom_offset(addr);
+                self.prg_rom[offset] = data;
+            }
+            _ => {}
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.ram.data
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            PRG_ROM..=0xFFFF => Some(self.rom_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+// Mapper 4. $8000/$8001 select and load the bank registers, R6 and R7 being the 8 KiB PRG
+// banks; $A001 bit 7 enables work RAM and bit 6 write-protects it. The scanline IRQ
+// ($C000-$FFFF) needs PPU A12 and is not emulated yet.
+#[derive(Clone)]
+struct Mmc3 {
+    prg_rom: Vec<u8>,
+    ram: WorkRam,
+    bank_select: u8,
+    registers: [u8; 8],
+}
+
+impl Mmc3 {
+    fn new(prg_rom: Vec<u8>, ram: WorkRam) -> Self {
+        Mmc3 {
+            prg_rom,
+            ram,
+            bank_select: 0,
+            registers: [0; 8],
+        }
+    }
+
+    // only called for $8000-$FFFF
+    fn rom_offset(&self, addr: u16) -> usize {
+        const BANK: usize = 0x2000;
+        let last = self.prg_rom.len() / BANK - 1;
+        let r6 = (self.registers[6] & 0x3f) as usize;
+        let r7 = (self.registers[7] & 0x3f) as usize;
+        let swapped = self.bank_select & 0x40 != 0;
+        let bank = match addr {
+            0x8000..=0x9FFF if swapped => last - 1,
+            0x8000..=0x9FFF => r6,
+            0xA000..=0xBFFF => r7,
+            0xC000..=0xDFFF if swapped => r6,
+            0xC000..=0xDFFF => last - 1,
+            _ => last,
+        };
+        banked(&self.prg_rom, BANK, bank, addr)
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn peek_prg(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.ram.peek(addr),
+            PRG_ROM..=0xFFFF => Some(self.prg_rom[self.rom_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.ram.write(addr, data),
+            0x8000..=0x9FFF if even => self.bank_select = data,
+            0x8000..=0x9FFF => self.registers[(self.bank_select & 0b111) as usize] = data,
+            0xA000..=0xBFFF if even => {} // mirroring
+            0xA000..=0xBFFF => {
+                self.ram.enabled = data & 0x80 != 0;
+                self.ram.write_protected = data & 0x40 != 0;
+            }
+            _ => {} // IRQ latch, reload, disable and enable
+        }
+    }
+
+    fn patch_prg(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => self.ram.patch(addr, data),
+            PRG_ROM..=0xFFFF => {
+                let offset = self.rom_offset(addr);
+                self.prg_rom[offset] = data;
+            }
+            _ => {}
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.ram.data
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            PRG_ROM..=0xFFFF => Some(self.rom_offset(addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn box_clone(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // every byte holds the number of its 8 KiB bank
+    fn numbered_banks(count: usize) -> Vec<u8> {
+        (0..count).flat_map(|bank| vec![bank as u8; 0x2000]).collect()
+    }
+
+    #[test]
+    fn test_nrom_mirrors_16k() {
+        let mut nrom = new(0, numbered_banks(2), 0x2000);
+        assert_eq!(nrom.read_prg(0x8000, 0), 0);
+        assert_eq!(nrom.read_prg(0xa000, 0), 1);
+        assert_eq!(nrom.read_prg(0xe000, 0), 1);
+
+        nrom.write_prg(0x7fff, 0x22);
+        assert_eq!(nrom.read_prg(0x7fff, 0), 0x22);
+        assert_eq!(nrom.prg_ram()[0x1fff], 0x22);
+    }
+
+    fn mmc1_write(mmc1: &mut Box<dyn Mapper>, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1.write_prg(addr, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_prg_banks() {
+        // 8 banks of 16 KiB
+        let mut mmc1 = new(1, numbered_banks(16), 0x2000);
+        assert_eq!(mmc1.read_prg(0xc000, 0), 14); // last bank fixed out of power-up
+
+        mmc1_write(&mut mmc1, 0xe000, 3);
+        assert_eq!(mmc1.read_prg(0x8000, 0), 6);
+        assert_eq!(mmc1.read_prg(0xa000, 0), 7);
+        assert_eq!(mmc1.read_prg(0xc000, 0), 14);
+
+        // first bank fixed at $8000
+        mmc1_write(&mut mmc1, 0x8000, 0b01000);
+        assert_eq!(mmc1.read_prg(0x8000, 0), 0);
+        assert_eq!(mmc1.read_prg(0xc000, 0), 6);
+
+        // a write with bit 7 set resets the shift register and the PRG mode
+        mmc1.write_prg(0x8000, 1);
+        mmc1.write_prg(0x8000, 0x80);
+        assert_eq!(mmc1.read_prg(0xc000, 0), 14);
+    }
+
+    #[test]
+    fn test_mmc1_disables_work_ram() {
+        let mut mmc1 = new(1, numbered_banks(4), 0x2000);
+        mmc1.write_prg(0x6000, 0x11);
+        mmc1_write(&mut mmc1, 0xe000, 0x10);
+        assert_eq!(mmc1.read_prg(0x6000, 0x60), 0x60);
+        mmc1.write_prg(0x6000, 0x22);
+
+        mmc1_write(&mut mmc1, 0xe000, 0x00);
+        assert_eq!(mmc1.read_prg(0x6000, 0x60), 0x11);
+    }
+
+    #[test]
+    fn test_mmc3_prg_banks() {
+        let mut mmc3 = new(4, numbered_banks(16), 0x2000);
+        mmc3.write_prg(0x8000, 6);
+        mmc3.write_prg(0x8001, 3);
+        mmc3.write_prg(0x8000, 7);
+        mmc3.write_prg(0x8001, 5);
+        let banks = |mmc3: &mut Box<dyn Mapper>| {
+            [0x8000, 0xa000, 0xc000, 0xe000].iter().map(|a| mmc3.read_prg(*a, 0)).collect::<Vec<u8>>()
+        };
+        assert_eq!(banks(&mut mmc3), vec![3, 5, 14, 15]);
+
+        mmc3.write_prg(0x8000, 0x40); // swap $8000 and $C000
+        assert_eq!(banks(&mut mmc3), vec![14, 5, 3, 15]);
+    }
+
+    #[test]
+    fn test_patch_prg_follows_the_banks() {
+        let mut mmc3 = new(4, numbered_banks(16), 0x2000);
+        mmc3.write_prg(0x8000, 6);
+        mmc3.write_prg(0x8001, 3);
+        mmc3.patch_prg(0x8010, 0xaa);
+        assert_eq!(mmc3.read_prg(0x8010, 0), 0xaa);
+        assert_eq!(mmc3.read_prg(0x8000, 0), 3);
+
+        // the patched byte moves with bank 3
+        mmc3.write_prg(0x8000, 7);
+        mmc3.write_prg(0x8001, 3);
+        assert_eq!(mmc3.read_prg(0xa010, 0), 0xaa);
+        mmc3.write_prg(0x8000, 6);
+        mmc3.write_prg(0x8001, 4);
+        assert_eq!(mmc3.read_prg(0x8010, 0), 4);
+
+        let mut nrom = new(0, numbered_banks(2), 0);
+        nrom.patch_prg(0xfffc, 0x42);
+        assert_eq!(nrom.read_prg(0xbffc, 0), 0x42);
+    }
+
+    #[test]
+    fn test_mmc3_work_ram_protection() {
+        let mut mmc3 = new(4, numbered_banks(4), 0x2000);
+        mmc3.write_prg(0x6123, 0x11);
+
+        mmc3.write_prg(0xa001, 0b1100_0000); // enabled, write-protected
+        mmc3.write_prg(0x6123, 0x99);
+        assert_eq!(mmc3.read_prg(0x6123, 0x61), 0x11);
+
+        mmc3.write_prg(0xa001, 0b0000_0000); // chip disabled
+        assert_eq!(mmc3.read_prg(0x6123, 0x61), 0x61);
+
+        mmc3.write_prg(0xa001, 0b1000_0000);
+        mmc3.write_prg(0x6123, 0x99);
+        assert_eq!(mmc3.read_prg(0x6123, 0x61), 0x99);
+    }
+}
@@ -0,0 +1,140 @@
+// Golden log check: runs nestest.nes headless from $C000 and compares a trace line per
+// instruction with the log of a known-good emulator, the best regression test the CPU has.
+
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::trace::trace;
+use std::fmt;
+use std::fs;
+
+pub const ROM_PATH: &str = "dump/nestest.nes";
+pub const LOG_PATH: &str = "log/nestest.log";
+
+// Lines to check. The log leaves the official opcodes after line 5003; Some(5003) skips the
+// unofficial ones while they are being worked on, None runs the whole log.
+pub const OFFICIAL_ONLY_UNTIL: Option<usize> = None;
+// Our PPU runs 314 dots a scanline instead of 341, so its position only agrees with the log for
+// the first scanline. The PPU column is blanked on both sides while this is false.
+pub const COMPARE_PPU: bool = false;
+const CONTEXT: usize = 5;
+
+// The first line where the trace and the log disagree, with the lines before it
+#[derive(Debug)]
+pub struct Divergence {
+    pub line: usize, // 1-based, as in an editor
+    pub expected: String,
+    pub actual: String,
+    pub context: Vec<String>, // the matching lines just before, oldest first
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "trace diverges from the log at line {}", self.line)?;
+        for (i, line) in self.context.iter().enumerate() {
+            writeln!(f, "{:5}      {}", self.line - self.context.len() + i, line)?;
+        }
+        writeln!(f, "{:5} log: {}", self.line, self.expected)?;
+        writeln!(f, "      emu: {}", self.actual)?;
+        let column = self
+            .expected
+            .chars()
+            .zip(self.actual.chars())
+            .position(|(a, b)| a != b)
+            .unwrap_or_else(|| self.expected.len().min(self.actual.len()));
+        write!(f, "           {}^", " ".repeat(column))
+    }
+}
+
+fn without_ppu(line: &str) -> String {
+    match (line.find(" PPU:"), line.find(" CYC:")) {
+        (Some(start), Some(end)) => format!("{}{}", &line[..start], &line[end..]),
+        _ => line.to_string(),
+    }
+}
+
+// The machine nestest's automated mode expects: powered on, PC forced to $C000
+pub fn nestest_cpu(rom: &Vec<u8>) -> CPU {
+    let mut cpu = CPU::new(Bus::new(Rom::new(rom).unwrap()));
+    cpu.power_on();
+    cpu.set_program_counter(0xc000);
+    cpu
+}
+
+// Steps `cpu` once per log line, up to `limit` lines. Ok with the number of lines compared.
+// A step that fails shows up as the error text in place of the next trace line.
+pub fn compare_with_log(
+    cpu: &mut CPU,
+    log: &str,
+    limit: Option<usize>,
+) -> Result<usize, Divergence> {
+    let mut compared = 0;
+    let mut context: Vec<String> = vec![];
+    let mut failed: Option<String> = None;
+    let lines = log.trim_end().lines().take(limit.unwrap_or(usize::MAX));
+    for (n, expected) in lines.enumerate() {
+        let actual = match failed.take() {
+            Some(err) => format!("({})", err),
+            None => trace(cpu),
+        };
+        let (expected, actual) = if COMPARE_PPU {
+            (expected.to_string(), actual)
+        } else {
+            (without_ppu(expected), without_ppu(&actual))
+        };
+        if expected != actual {
+            return Err(Divergence { line: n + 1, expected, actual, context });
+        }
+        compared += 1;
+        if context.len() == CONTEXT {
+            context.remove(0);
+        }
+        context.push(expected);
+        if let Err(e) = cpu.step() {
+            failed = Some(e.to_string());
+        }
+    }
+    Ok(compared)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_divergence_report() {
+        let log = "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7\n\
+                   C5F5  A2 01     LDX #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10\n";
+        let mut cpu = nestest_cpu(&fs::read(ROM_PATH).unwrap());
+        let report = compare_with_log(&mut cpu, log, None).unwrap_err();
+        assert_eq!(report.line, 2);
+        assert_eq!(
+            report.to_string(),
+            "trace diverges from the log at line 2\n    \
+             1      C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7\n    \
+             2 log: C5F5  A2 01     LDX #$01                        A:00 X:00 Y:00 P:24 SP:FD CYC:10\n      \
+             emu: C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD CYC:10\n                     ^"
+        );
+    }
+
+    // cargo test -- --ignored nestest
+    #[test]
+    #[ignore]
+    fn test_nestest_golden_log() {
+        let (rom, log) = match (fs::read(ROM_PATH), fs::read_to_string(LOG_PATH)) {
+            (Ok(rom), Ok(log)) => (rom, log),
+            _ => {
+                eprintln!("skipped, {} or {} is missing", ROM_PATH, LOG_PATH);
+                return;
+            }
+        };
+        let mut cpu = nestest_cpu(&rom);
+        match compare_with_log(&mut cpu, &log, OFFICIAL_ONLY_UNTIL) {
+            Ok(lines) => {
+                let all = log.trim_end().lines().count();
+                assert_eq!(lines, OFFICIAL_ONLY_UNTIL.unwrap_or(all));
+            }
+            Err(divergence) => panic!("\n{}", divergence),
+        }
+    }
+}
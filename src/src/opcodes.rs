@@ -1,26 +1,113 @@
-use crate::cpu::AddressingMode;
+use crate::cpu::{AddressingMode, Mem};
 use std::collections::HashMap;
 
 pub struct OpCode {
     pub code: u8,
-    pub mnemonic: &'static str,
-    pub len: u8,
-    pub cycles: u8,
+    pub mnemonic: &'static str, // unofficial opcodes are prefixed with '*'
+    pub bytes: u8, // opcode plus operand
+    pub cycles: u8, // base cycles, without page-cross or branch-taken penalties
     pub mode: AddressingMode,
+    pub page_cross_penalty: bool,
+    pub official: bool,
+    pub unstable: bool, // result depends on the chip and bus conditions
 }
 
 impl OpCode {
-    fn new(code: u8, mnemonic: &'static str, len: u8, cycles: u8, mode: AddressingMode) -> Self {
+    fn new(code: u8, mnemonic: &'static str, bytes: u8, cycles: u8, mode: AddressingMode) -> Self {
         OpCode {
             code: code,
             mnemonic: mnemonic,
-            len: len,
+            bytes: bytes,
             cycles: cycles,
+            page_cross_penalty: has_page_cross_penalty(mnemonic, &mode),
             mode: mode,
+            official: !mnemonic.starts_with('*'),
+            unstable: matches!(mnemonic, "*SHA" | "*SHX" | "*SHY" | "*TAS" | "*LAS"),
+        }
+    }
+
+    // the NMOS opcode `code`, official or not
+    pub fn lookup(code: u8) -> Option<&'static OpCode> {
+        OPCODES_TABLE[code as usize]
+    }
+
+    // every NMOS opcode, in table order
+    pub fn iter() -> impl Iterator<Item = &'static OpCode> {
+        CPU_OPS_CODES.iter()
+    }
+
+    // format_operand for the instruction at `pc`, reading its operand from memory
+    pub fn format_operand_at(&self, mem: &mut dyn Mem, pc: u16) -> String {
+        let operand: Vec<u8> = (1..self.bytes as u16)
+            .map(|i| mem.mem_read(pc.wrapping_add(i)))
+            .collect();
+        self.format_operand(&operand, pc)
+    }
+
+    // Operand in assembler syntax. `pc` is the address of the opcode byte, used to resolve
+    // relative branches. Shared by the disassembler and the tracer so they can't drift apart.
+    pub fn format_operand(&self, operand: &[u8], pc: u16) -> String {
+        let byte = operand.first().copied().unwrap_or(0);
+        let word = (operand.get(1).copied().unwrap_or(0) as u16) << 8 | byte as u16;
+
+        match self.mode {
+            AddressingMode::Immediate => format!("#${:02X}", byte),
+            AddressingMode::ZeroPage => format!("${:02X}", byte),
+            AddressingMode::ZeroPage_X => format!("${:02X},X", byte),
+            AddressingMode::ZeroPage_Y => format!("${:02X},Y", byte),
+            AddressingMode::Absolute => format!("${:04X}", word),
+            AddressingMode::Absolute_X => format!("${:04X},X", word),
+            AddressingMode::Absolute_Y => format!("${:04X},Y", word),
+            AddressingMode::Indirect_X => format!("(${:02X},X)", byte),
+            AddressingMode::Indirect_Y => format!("(${:02X}),Y", byte),
+            AddressingMode::ZeroPage_Indirect => format!("(${:02X})", byte),
+            AddressingMode::Relative => format!("${:04X}", self.target(operand, pc).unwrap_or(word)),
+            AddressingMode::Indirect => format!("(${:04X})", word),
+            AddressingMode::NoneAddressing => match self.bytes {
+                // shifts, and the 65C02's INC A / DEC A, work on the accumulator
+                1 if matches!(self.mnemonic, "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC") => {
+                    String::from("A")
+                }
+                1 => String::new(),
+                _ => format!("${:04X}", word), // JSR
+            },
+        }
+    }
+
+    // destination of JMP absolute, JSR and relative branches; JMP indirect reads its
+    // target at run time so it has none
+    pub fn target(&self, operand: &[u8], pc: u16) -> Option<u16> {
+        let byte = operand.first().copied().unwrap_or(0);
+        let word = (operand.get(1).copied().unwrap_or(0) as u16) << 8 | byte as u16;
+
+        match self.mode {
+            AddressingMode::Relative => Some(pc.wrapping_add(2).wrapping_add((byte as i8) as u16)),
+            AddressingMode::Absolute if self.mnemonic == "JMP" => Some(word),
+            AddressingMode::NoneAddressing if self.bytes == 3 => Some(word), // JSR
+            _ => None,
         }
     }
 }
 
+// Indexed reads take an extra cycle when the index carries into the high byte. Stores and
+// read-modify-write instructions always spend that cycle, so it is part of their base count.
+fn has_page_cross_penalty(mnemonic: &str, mode: &AddressingMode) -> bool {
+    let indexed = matches!(
+        mode,
+        AddressingMode::Absolute_X | AddressingMode::Absolute_Y | AddressingMode::Indirect_Y
+    );
+    let reads_only = matches!(
+        mnemonic.trim_start_matches('*'),
+        "ADC" | "AND" | "BIT" | "CMP" | "EOR" | "LDA" | "LDX" | "LDY" | "ORA" | "SBC" | "LAX"
+            | "LAS" | "NOP"
+    );
+    indexed && reads_only
+}
+
+pub fn lookup(code: u8) -> Option<&'static OpCode> {
+    OpCode::lookup(code)
+}
+
 lazy_static! {
     pub static ref CPU_OPS_CODES: Vec<OpCode> = vec![
         OpCode::new(0x00, "BRK", 1, 7, AddressingMode::NoneAddressing),
@@ -133,22 +220,22 @@ lazy_static! {
 
         /* Branching */
 
-        OpCode::new(0x4c, "JMP", 3, 3, AddressingMode::NoneAddressing), //AddressingMode that acts as Immidiate
-        OpCode::new(0x6c, "JMP", 3, 5, AddressingMode::NoneAddressing), //AddressingMode:Indirect with 6502 bug
+        OpCode::new(0x4c, "JMP", 3, 3, AddressingMode::Absolute),
+        OpCode::new(0x6c, "JMP", 3, 5, AddressingMode::Indirect), // with the $xxFF page wrap bug
 
         OpCode::new(0x20, "JSR", 3, 6, AddressingMode::NoneAddressing),
         OpCode::new(0x60, "RTS", 1, 6, AddressingMode::NoneAddressing),
 
         OpCode::new(0x40, "RTI", 1, 6, AddressingMode::NoneAddressing),
 
-        OpCode::new(0xd0, "BNE", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x70, "BVS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x50, "BVC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x30, "BMI", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0xf0, "BEQ", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0xb0, "BCS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x90, "BCC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
-        OpCode::new(0x10, "BPL", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::NoneAddressing),
+        OpCode::new(0xd0, "BNE", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x70, "BVS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x50, "BVC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x30, "BMI", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0xf0, "BEQ", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0xb0, "BCS", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x90, "BCC", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
+        OpCode::new(0x10, "BPL", 2, 2 /*(+1 if branch succeeds +2 if to a new page)*/, AddressingMode::Relative),
 
         OpCode::new(0x24, "BIT", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0x2c, "BIT", 3, 4, AddressingMode::Absolute),
@@ -307,18 +394,18 @@ lazy_static! {
         OpCode::new(0xe3, "*ISB", 2,8, AddressingMode::Indirect_X),
         OpCode::new(0xf3, "*ISB", 2,8, AddressingMode::Indirect_Y),
 
-        OpCode::new(0x02, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x12, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x22, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x32, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x42, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x52, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x62, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x72, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x92, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xb2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xd2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xf2, "*NOP", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x02, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x12, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x22, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x32, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x42, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x52, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x62, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x72, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x92, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0xb2, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0xd2, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0xf2, "*JAM", 1,2, AddressingMode::NoneAddressing),
 
         OpCode::new(0x1a, "*NOP", 1,2, AddressingMode::NoneAddressing),
         OpCode::new(0x3a, "*NOP", 1,2, AddressingMode::NoneAddressing),
@@ -331,12 +418,12 @@ lazy_static! {
         OpCode::new(0xab, "*LXA", 2, 3, AddressingMode::Immediate), //todo: highly unstable and not used
         //http://visual6502.org/wiki/index.php?title=6502_Opcode_8B_%28XAA,_ANE%29
         OpCode::new(0x8b, "*XAA", 2, 3, AddressingMode::Immediate), //todo: highly unstable and not used
-        OpCode::new(0xbb, "*LAS", 3, 2, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpCode::new(0x9b, "*TAS", 3, 2, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpCode::new(0x93, "*AHX", 2, /* guess */ 8, AddressingMode::Indirect_Y), //todo: highly unstable and not used
-        OpCode::new(0x9f, "*AHX", 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpCode::new(0x9e, "*SHX", 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_Y), //todo: highly unstable and not used
-        OpCode::new(0x9c, "*SHY", 3, /* guess */ 4/* or 5*/, AddressingMode::Absolute_X), //todo: highly unstable and not used
+        OpCode::new(0xbb, "*LAS", 3, 4 /*or 5*/, AddressingMode::Absolute_Y),
+        OpCode::new(0x9b, "*TAS", 3, 5, AddressingMode::Absolute_Y),
+        OpCode::new(0x93, "*SHA", 2, 6, AddressingMode::Indirect_Y),
+        OpCode::new(0x9f, "*SHA", 3, 5, AddressingMode::Absolute_Y),
+        OpCode::new(0x9e, "*SHX", 3, 5, AddressingMode::Absolute_Y),
+        OpCode::new(0x9c, "*SHY", 3, 5, AddressingMode::Absolute_X),
 
         OpCode::new(0xa7, "*LAX", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0xb7, "*LAX", 2, 4, AddressingMode::ZeroPage_Y),
@@ -360,4 +447,207 @@ lazy_static! {
         }
         map
     };
+
+    // direct-indexed copy of OPCODES_MAP for the CPU hot loop, avoids hashing every fetch
+    pub static ref OPCODES_TABLE: [Option<&'static OpCode>; 256] = {
+        let mut table = [None; 256];
+        for cpuop in &*CPU_OPS_CODES {
+            table[cpuop.code as usize] = Some(cpuop);
+        }
+        table
+    };
+
+    // CMOS additions and replacements, layered over the official NMOS opcodes
+    pub static ref CPU_65C02_OPS_CODES: Vec<OpCode> = vec![
+        OpCode::new(0xda, "PHX", 1, 3, AddressingMode::NoneAddressing),
+        OpCode::new(0xfa, "PLX", 1, 4, AddressingMode::NoneAddressing),
+        OpCode::new(0x5a, "PHY", 1, 3, AddressingMode::NoneAddressing),
+        OpCode::new(0x7a, "PLY", 1, 4, AddressingMode::NoneAddressing),
+
+        OpCode::new(0x64, "STZ", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(0x74, "STZ", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x9c, "STZ", 3, 4, AddressingMode::Absolute),
+        OpCode::new(0x9e, "STZ", 3, 5, AddressingMode::Absolute_X),
+
+        OpCode::new(0x80, "BRA", 2, 2 /*+1 taken, +1 if page crossed*/, AddressingMode::Relative),
+
+        OpCode::new(0x1a, "INC", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x3a, "DEC", 1, 2, AddressingMode::NoneAddressing),
+
+        OpCode::new(0x04, "TSB", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x0c, "TSB", 3, 6, AddressingMode::Absolute),
+        OpCode::new(0x14, "TRB", 2, 5, AddressingMode::ZeroPage),
+        OpCode::new(0x1c, "TRB", 3, 6, AddressingMode::Absolute),
+
+        OpCode::new(0x89, "BIT", 2, 2, AddressingMode::Immediate),
+        OpCode::new(0x34, "BIT", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x3c, "BIT", 3, 4/*+1 if page crossed*/, AddressingMode::Absolute_X),
+
+        OpCode::new(0x12, "ORA", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x32, "AND", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x52, "EOR", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x72, "ADC", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0x92, "STA", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0xb2, "LDA", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0xd2, "CMP", 2, 5, AddressingMode::ZeroPage_Indirect),
+        OpCode::new(0xf2, "SBC", 2, 5, AddressingMode::ZeroPage_Indirect),
+
+        OpCode::new(0x6c, "JMP", 3, 6, AddressingMode::Indirect), // page wrap bug fixed
+    ];
+
+    // the unofficial NMOS opcodes do not exist on the 65C02, so they are left out
+    pub static ref OPCODES_65C02_TABLE: [Option<&'static OpCode>; 256] = {
+        let mut table = [None; 256];
+        for cpuop in CPU_OPS_CODES.iter().filter(|op| !op.mnemonic.starts_with('*')) {
+            table[cpuop.code as usize] = Some(cpuop);
+        }
+        for cpuop in &*CPU_65C02_OPS_CODES {
+            table[cpuop.code as usize] = Some(cpuop);
+        }
+        table
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expected_len(mode: &AddressingMode) -> Option<u8> {
+        match mode {
+            AddressingMode::Immediate
+            | AddressingMode::ZeroPage
+            | AddressingMode::ZeroPage_X
+            | AddressingMode::ZeroPage_Y
+            | AddressingMode::Indirect_X
+            | AddressingMode::Indirect_Y
+            | AddressingMode::ZeroPage_Indirect => Some(2),
+            AddressingMode::Relative => Some(2),
+            AddressingMode::Absolute
+            | AddressingMode::Absolute_X
+            | AddressingMode::Absolute_Y
+            | AddressingMode::Indirect => Some(3),
+            AddressingMode::NoneAddressing => None,
+        }
+    }
+
+    #[test]
+    fn test_every_opcode_is_consistent() {
+        for table in [&*OPCODES_TABLE, &*OPCODES_65C02_TABLE].iter() {
+            for (byte, op) in table.iter().enumerate() {
+                let op = match op {
+                    Some(op) => op,
+                    None => continue,
+                };
+                assert_eq!(op.code as usize, byte);
+                match expected_len(&op.mode) {
+                    Some(len) => assert_eq!(op.bytes, len, "{:02x} {}", byte, op.mnemonic),
+                    None => assert!((1..=3).contains(&op.bytes), "{:02x} {}", byte, op.mnemonic),
+                }
+                assert_eq!(op.official, !op.mnemonic.starts_with('*'));
+                assert!(op.cycles >= 2, "{:02x} {}", byte, op.mnemonic);
+            }
+        }
+        let official = OPCODES_TABLE.iter().flatten().filter(|op| op.official).count();
+        assert_eq!(official, 151);
+    }
+
+    #[test]
+    fn test_page_cross_penalty() {
+        assert!(lookup(0xbd).unwrap().page_cross_penalty); // LDA abs,X
+        assert!(lookup(0xb1).unwrap().page_cross_penalty); // LDA (zp),Y
+        assert!(lookup(0x1c).unwrap().page_cross_penalty); // *NOP abs,X
+        assert!(!lookup(0x9d).unwrap().page_cross_penalty); // STA abs,X
+        assert!(!lookup(0x1e).unwrap().page_cross_penalty); // ASL abs,X
+        assert!(!lookup(0xb5).unwrap().page_cross_penalty); // LDA zp,X
+        assert!(lookup(0xbb).unwrap().page_cross_penalty); // *LAS abs,Y
+        assert!(!lookup(0x9f).unwrap().page_cross_penalty); // *SHA abs,Y
+    }
+
+    #[test]
+    fn test_unstable_opcodes() {
+        let unstable: Vec<u8> = OPCODES_TABLE
+            .iter()
+            .flatten()
+            .filter(|op| op.unstable)
+            .map(|op| op.code)
+            .collect();
+        assert_eq!(unstable, vec![0x93, 0x9b, 0x9c, 0x9e, 0x9f, 0xbb]);
+    }
+
+    #[test]
+    fn test_format_operand() {
+        let cases: [(u8, &[u8], &str); 12] = [
+            (0xa9, &[0x10], "#$10"),
+            (0xa5, &[0x10], "$10"),
+            (0xb5, &[0x10], "$10,X"),
+            (0xb6, &[0x10], "$10,Y"),
+            (0xad, &[0x34, 0x12], "$1234"),
+            (0xbd, &[0x34, 0x12], "$1234,X"),
+            (0xb9, &[0x34, 0x12], "$1234,Y"),
+            (0xa1, &[0x10], "($10,X)"),
+            (0xb1, &[0x10], "($10),Y"),
+            (0x0a, &[], "A"),
+            (0xea, &[], ""),
+            (0x20, &[0x34, 0x12], "$1234"),
+        ];
+        for (code, operand, expected) in cases.iter() {
+            assert_eq!(lookup(*code).unwrap().format_operand(operand, 0x8000), *expected);
+        }
+
+        let lda_zp_indirect = OPCODES_65C02_TABLE[0xb2].unwrap();
+        assert_eq!(lda_zp_indirect.format_operand(&[0x10], 0), "($10)");
+        assert_eq!(OPCODES_65C02_TABLE[0x1a].unwrap().format_operand(&[], 0), "A");
+        assert_eq!(lookup(0x1a).unwrap().format_operand(&[], 0), "");
+        assert_eq!(lookup(0x20).unwrap().target(&[0x34, 0x12], 0), Some(0x1234));
+        assert_eq!(lookup(0x6c).unwrap().target(&[0x34, 0x12], 0), None);
+        let bne = lookup(0xd0).unwrap();
+        assert_eq!(bne.format_operand(&[0xfe], 0x8000), "$8000");
+        assert_eq!(lookup(0x6c).unwrap().format_operand(&[0xff, 0x02], 0), "($02FF)");
+        assert_eq!(lookup(0xb1).unwrap().format_operand(&[0x10], 0), "($10),Y");
+    }
+
+    #[test]
+    fn test_lookup_and_iter() {
+        let lda = OpCode::lookup(0xb1).unwrap();
+        assert_eq!((lda.code, lda.mnemonic, lda.bytes, lda.cycles), (0xb1, "LDA", 2, 5));
+        assert_eq!(lda.mode, AddressingMode::Indirect_Y);
+        assert!(lda.official && lda.page_cross_penalty);
+        let slo = OpCode::lookup(0x07).unwrap();
+        assert_eq!((slo.mnemonic, slo.official), ("*SLO", false));
+        assert_eq!(OpCode::lookup(0x6c).unwrap().mode, AddressingMode::Indirect);
+        assert!(OpCode::lookup(0x0b).is_some());
+
+        assert_eq!(OpCode::iter().filter(|op| op.official).count(), 151);
+        for op in OpCode::iter() {
+            assert!(std::ptr::eq(OpCode::lookup(op.code).unwrap(), op), "{:02x}", op.code);
+        }
+    }
+
+    #[test]
+    fn test_format_operand_matches_nestest() {
+        use crate::cpu_builder::CpuBuilder;
+
+        // address, bytes, what nestest.log shows before any "= value" annotation
+        let lines: [(u16, &[u8], &str); 9] = [
+            (0xc000, &[0x4c, 0xf5, 0xc5], "JMP $C5F5"),
+            (0xc5f5, &[0xa2, 0x00], "LDX #$00"),
+            (0xc5f7, &[0x86, 0x00], "STX $00"),
+            (0xdb7b, &[0x6c, 0x00, 0x02], "JMP ($0200)"),
+            (0xc72f, &[0xb0, 0x04], "BCS $C735"),
+            (0xd922, &[0xb1, 0x89], "LDA ($89),Y"),
+            (0xcfdb, &[0xa1, 0x80], "LDA ($80,X)"),
+            (0xcefc, &[0x4a], "LSR A"),
+            (0xe19d, &[0x99, 0x00, 0x04], "STA $0400,Y"),
+        ];
+        let mut builder = CpuBuilder::new();
+        for (addr, bytes, _) in lines.iter() {
+            builder = builder.mem(*addr, bytes);
+        }
+        let mut cpu = builder.build();
+        for (addr, bytes, expected) in lines.iter() {
+            let op = OpCode::lookup(bytes[0]).unwrap();
+            let text = format!("{} {}", op.mnemonic, op.format_operand_at(&mut cpu, *addr));
+            assert_eq!(text.trim_end(), *expected);
+        }
+    }
 }
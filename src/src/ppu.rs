@@ -1,11 +1,69 @@
 
 
 use crate::cartridge::Mirroring;
+use crate::clock::Region;
 use crate::ppu_registers::{AddrRegister, ControlRegister, PPURegister, MaskRegister, StatusRegister, ScrollRegister};
 
 const  MAX_CYCLE:usize = 314;
-const MAX_SCAN_LINE:usize = 261;
+const VISIBLE_SCAN_LINES: usize = 240;
+const SPRITES_PER_LINE: usize = 8;
+
+// $3F20-$3FFF mirror $3F00-$3F1F, and $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1f) as usize;
+    if index & 0x13 == 0x10 {
+        index - 0x10
+    } else {
+        index
+    }
+}
+
+pub const FRAME_WIDTH: usize = 256;
+pub const FRAME_HEIGHT: usize = VISIBLE_SCAN_LINES;
+const OVERLAY_COLOR: [u8; 3] = [0x00, 0xff, 0x00];
+const DROPPED_COLOR: [u8; 3] = [0xff, 0x00, 0x00];
+
+// Screen-space view of one OAM entry, for debug overlays
+#[derive(Debug, PartialEq, Eq)]
+pub struct SpriteBox {
+    pub oam_index: u8,
+    pub x: u8,
+    pub y: u16, // OAM Y + 1: sprites are displayed one line below their OAM position
+    pub width: u8,
+    pub height: u8,
+    pub tile: u8,
+    pub palette: u8, // 4..=7, sprites use the upper four palettes
+    pub dropped: bool, // lost to the 8-sprites-per-scanline limit on at least one line
+}
+
+// Copy of an RGB24 frame with a one pixel outline around each box, red for dropped sprites
+pub fn draw_sprite_overlay(frame: &[u8], boxes: &[SpriteBox]) -> Vec<u8> {
+    assert_eq!(frame.len(), FRAME_WIDTH * FRAME_HEIGHT * 3);
+    let mut result = frame.to_vec();
+    let mut plot = |x: usize, y: usize, color: [u8; 3]| {
+        if x < FRAME_WIDTH && y < FRAME_HEIGHT {
+            let idx = (y * FRAME_WIDTH + x) * 3;
+            result[idx..idx + 3].copy_from_slice(&color);
+        }
+    };
+
+    for b in boxes {
+        let color = if b.dropped { DROPPED_COLOR } else { OVERLAY_COLOR };
+        let (left, top) = (b.x as usize, b.y as usize);
+        let (right, bottom) = (left + b.width as usize - 1, top + b.height as usize - 1);
+        for x in left..=right {
+            plot(x, top, color);
+            plot(x, bottom, color);
+        }
+        for y in top..=bottom {
+            plot(left, y, color);
+            plot(right, y, color);
+        }
+    }
+    result
+}
 
+#[derive(Clone)]
 pub struct PPU{
     chr_rom: Vec<u8>,   // visuals of a game stored on a cartridge
     palette_table: [u8; 32],    // internal memory to keep palette tables used by a screen
@@ -13,6 +71,7 @@ pub struct PPU{
     oam_data: [u8; 256], // internal memory to keep state of sprites, OAM => Object Attribute Memory
 
     mirroring: Mirroring,
+    region: Region,
 
     internal_data_buf: u8, // internal buffer behavior for RAM and ROM: read [0x2007] in CPU will return this data
 
@@ -20,6 +79,8 @@ pub struct PPU{
     pub clock_cycles: usize,
     pub scan_lines: usize,
     nmi_irq: Option<u8>,
+    frame: Vec<u8>, // palette values, FRAME_WIDTH x FRAME_HEIGHT
+    frames: u64, // vblanks started, each one finishing a picture
     
 
 
@@ -51,6 +112,7 @@ impl PPU{
         PPU{
             chr_rom: chr_rom,
             mirroring: mirroring,
+            region: Region::default(),
             vram: [0; 2048],
             oam_data: [0; 64 * 4],
             palette_table: [0; 32],
@@ -58,6 +120,8 @@ impl PPU{
             clock_cycles: 0,
             scan_lines: 0,
             nmi_irq: None,
+            frame: vec![0; FRAME_WIDTH * FRAME_HEIGHT],
+            frames: 0,
             reg_addr: AddrRegister::new(),
             reg_ctrl:ControlRegister::new(),
             reg_oam_addr: 0,
@@ -67,6 +131,15 @@ impl PPU{
         }
     }
 
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    // the pre-render line
+    fn last_scan_line(&self) -> usize {
+        self.region.scanlines_per_frame() - 1
+    }
+
     pub fn pull_nmi_irq(&mut self) -> Option<u8>{
         // take irq and leave num_irq to None
         self.nmi_irq.take()
@@ -114,13 +187,8 @@ impl PPU{
                 self.vram[(vram_addr as usize)] = value;
             }
             0x3000..=0x3eff => panic!("addr space 0x3000..0x3eff is not expected to be used, requested = {} ", addr),
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
-            }
             0x3f00..=0x3fff =>{
-                self.palette_table[(addr-0x3f00) as usize] = value;
+                self.palette_table[palette_index(addr)] = value;
             }
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
@@ -134,9 +202,105 @@ impl PPU{
         }
     }
 
+    // Sprites that fall on a visible scanline, in OAM order. Nothing is evaluated while both
+    // background and sprite rendering are off.
+    pub fn sprite_overlay(&self) -> Vec<SpriteBox> {
+        if !self.rendering_enabled() {
+            return vec![];
+        }
+        let height = self.reg_ctrl.sprite_size();
+        let mut per_line = [0usize; VISIBLE_SCAN_LINES];
+        let mut result = vec![];
+
+        for (idx, entry) in self.oam_data.chunks(4).enumerate() {
+            let y = entry[0] as u16 + 1;
+            if y as usize >= VISIBLE_SCAN_LINES {
+                continue;
+            }
+
+            let mut dropped = false;
+            let last_line = (y as usize + height as usize).min(VISIBLE_SCAN_LINES);
+            for count in per_line[(y as usize)..last_line].iter_mut() {
+                if *count == SPRITES_PER_LINE {
+                    dropped = true;
+                } else {
+                    *count += 1;
+                }
+            }
+
+            result.push(SpriteBox {
+                oam_index: idx as u8,
+                x: entry[3],
+                y,
+                width: 8,
+                height,
+                tile: entry[1],
+                palette: (entry[2] & 0b11) + 4,
+                dropped,
+            });
+        }
+        result
+    }
+
     // reading PPU memory
     fn increment_vram_addr(&mut self){
-        self.reg_addr.increment(self.reg_ctrl.vram_addr_increment());
+        if self.is_rendering() {
+            // $2007 access mid-render: v gets the coarse X and Y increments instead of +1/+32
+            self.reg_addr.increment_coarse_x();
+            self.reg_addr.increment_y();
+        } else {
+            self.reg_addr.increment(self.reg_ctrl.vram_addr_increment());
+        }
+    }
+
+    // Color (palette value) of a dot with nothing drawn on it: the universal backdrop at $3F00,
+    // except during forced blanking with v pointing into palette RAM, where the PPU outputs
+    // the entry v points at. Games use this to draw solid colors and gradients.
+    pub fn backdrop_color(&self) -> u8 {
+        let addr = self.reg_addr.get();
+        if !self.rendering_enabled() && (0x3f00..=0x3fff).contains(&addr) {
+            self.palette_table[palette_index(addr)]
+        } else {
+            self.palette_table[0]
+        }
+    }
+
+    // size of the cartridge's CHR-ROM, in bytes
+    pub fn chr_rom_len(&self) -> usize {
+        self.chr_rom.len()
+    }
+
+    // the picture as palette values (indices into the system palette), one byte per pixel
+    // frames finished so far, counting up as vblank starts
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    // Forced blanking: every visible dot gets the backdrop color. There is no background or
+    // sprite renderer yet, so dots drawn with rendering on keep their previous value.
+    fn output_blank_dots(&mut self, cycles: usize) {
+        if self.rendering_enabled() || self.scan_lines >= VISIBLE_SCAN_LINES {
+            return;
+        }
+        let color = self.backdrop_color();
+        let row = self.scan_lines * FRAME_WIDTH;
+        let start = self.clock_cycles.min(FRAME_WIDTH);
+        let end = (self.clock_cycles + cycles).min(FRAME_WIDTH);
+        for pixel in self.frame[row + start..row + end].iter_mut() {
+            *pixel = color;
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.reg_mask.is_leftmost_show_bg() || self.reg_mask.is_leftmost_show_sprite()
+    }
+
+    fn is_rendering(&self) -> bool {
+        self.rendering_enabled() && (self.scan_lines < VISIBLE_SCAN_LINES || self.scan_lines == self.last_scan_line())
     }
 
     pub fn read_ppu_status(&mut self) -> u8{
@@ -170,13 +334,8 @@ impl PPU{
                 result
             }
             0x3000..=0x3eff => panic!("addr space 0x3000..0x3eff is not expected to be used, requested = {} ", addr),
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
-            }
             0x3f00..=0x3fff =>{
-                self.palette_table[(addr-0x3f00) as usize]
+                self.palette_table[palette_index(addr)]
             }
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
@@ -209,11 +368,13 @@ impl PPU{
 
    // Main execution logic
    pub fn tick(&mut self, cycles: usize){
+        self.output_blank_dots(cycles);
         self.clock_cycles += cycles;
-        if self.clock_cycles >= MAX_CYCLE {
-            self.scan_lines += 1;
-            self.clock_cycles -= MAX_CYCLE;
+        if self.clock_cycles < MAX_CYCLE {
+            return;
         }
+        self.scan_lines += 1;
+        self.clock_cycles -= MAX_CYCLE;
 
         
         // match self.clock_cycles {
@@ -234,16 +395,16 @@ impl PPU{
 
         //     }
         // }
-        if self.scan_lines == 241{
-            
-            // generate irq
+        // vblank starts once, as the line begins; the flag is set whether or not NMIs are on
+        if self.scan_lines == self.region.vblank_scanline(){
+            self.frames += 1;
+            self.reg_status.set_vblank_status(true);
             if self.reg_ctrl.generate_vblank_nmi(){
-                self.reg_status.set_vblank_status(true);
                 self.nmi_irq = Some(1);
             }
         }
 
-        if self.scan_lines > MAX_SCAN_LINE{
+        if self.scan_lines > self.last_scan_line(){
             self.scan_lines = 0;
             self.reg_status.reset_vblank_status();
 
@@ -438,6 +599,231 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x77);
     }
 
+    #[test]
+    fn test_sprite_overlay() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_mask(0b0001_0000); // show sprites
+        // every sprite off-screen except the ones written below
+        for i in 0..64 {
+            ppu.oam_data[i * 4] = 0xff;
+        }
+        ppu.oam_data[0..4].copy_from_slice(&[0x10, 0x42, 0b0000_0010, 0x20]);
+        ppu.oam_data[4..8].copy_from_slice(&[0xee, 0x01, 0, 0x00]); // last visible line
+
+        let boxes = ppu.sprite_overlay();
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(
+            boxes[0],
+            SpriteBox {
+                oam_index: 0,
+                x: 0x20,
+                y: 0x11,
+                width: 8,
+                height: 8,
+                tile: 0x42,
+                palette: 6,
+                dropped: false,
+            }
+        );
+        assert_eq!(boxes[1].y, 0xef);
+
+        ppu.write_to_ctrl(0b0010_0000); // 8x16 sprites
+        assert_eq!(ppu.sprite_overlay()[0].height, 16);
+    }
+
+    #[test]
+    fn test_sprite_overlay_flags_ninth_sprite_on_a_line() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_mask(0b0001_0000); // show sprites
+        for i in 0..64 {
+            ppu.oam_data[i * 4] = 0xff;
+        }
+        for i in 0..9 {
+            ppu.oam_data[i * 4] = 0x30;
+            ppu.oam_data[i * 4 + 3] = (i * 8) as u8;
+        }
+        // partially overlapping the others, but only from line 0x35 on
+        ppu.oam_data[9 * 4] = 0x34;
+
+        let boxes = ppu.sprite_overlay();
+        assert_eq!(boxes.len(), 10);
+        assert!(boxes[..8].iter().all(|b| !b.dropped));
+        assert!(boxes[8].dropped);
+        assert!(boxes[9].dropped);
+    }
+
+    #[test]
+    fn test_no_sprites_evaluated_with_rendering_off() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.oam_data[0..4].copy_from_slice(&[0x10, 0x42, 0, 0x20]);
+        assert!(ppu.sprite_overlay().is_empty());
+        ppu.write_to_ppu_mask(0b0000_1000); // background only still runs evaluation
+        assert_eq!(ppu.sprite_overlay().len(), 64);
+    }
+
+    #[test]
+    fn test_draw_sprite_overlay() {
+        let frame = vec![0u8; FRAME_WIDTH * FRAME_HEIGHT * 3];
+        let sprite = |x, y, dropped| SpriteBox {
+            oam_index: 0,
+            x,
+            y,
+            width: 8,
+            height: 8,
+            tile: 0,
+            palette: 4,
+            dropped,
+        };
+        // the second box runs off the right and bottom edges
+        let drawn = draw_sprite_overlay(&frame, &[sprite(16, 10, false), sprite(252, 236, true)]);
+        let pixel = |x: usize, y: usize| &drawn[(y * FRAME_WIDTH + x) * 3..][..3];
+
+        for (x, y) in [(16, 10), (23, 10), (16, 17), (23, 17), (20, 10), (16, 14), (23, 14)].iter() {
+            assert_eq!(pixel(*x, *y), &OVERLAY_COLOR, "({}, {})", x, y);
+        }
+        assert_eq!(pixel(20, 14), &[0, 0, 0]); // the inside is left alone
+        assert_eq!(pixel(15, 10), &[0, 0, 0]);
+        assert_eq!(pixel(24, 17), &[0, 0, 0]);
+        assert_eq!(pixel(252, 236), &DROPPED_COLOR);
+        assert_eq!(pixel(255, 239), &[0, 0, 0]); // interior of the clipped box
+        assert_eq!(pixel(252, 239), &DROPPED_COLOR);
+        assert_eq!(drawn.iter().filter(|c| **c != 0).count(), 28 + 7); // one channel set per pixel
+        assert!(frame.iter().all(|c| *c == 0));
+    }
+
+    fn ppu_at(addr: u16) -> PPU {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr((addr & 0xff) as u8);
+        ppu
+    }
+
+    #[test]
+    fn test_data_access_while_rendering_glitches_v() {
+        // coarse X 5 -> 6, fine Y 2 -> 3
+        let mut ppu = ppu_at(0x2345);
+        ppu.write_to_ppu_mask(0b0000_1000); // show background
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.vram[0x0345], 0x66);
+        assert_eq!(ppu.reg_addr.get(), 0x3346);
+
+        // coarse X 31 wraps into the next horizontal nametable
+        let mut ppu = ppu_at(0x201f);
+        ppu.write_to_ppu_mask(0b0001_0000); // show sprites
+        ppu.scan_lines = 261; // pre-render line
+        ppu.read_data();
+        assert_eq!(ppu.reg_addr.get(), 0x3400);
+    }
+
+    #[test]
+    fn test_increment_y_wraps_coarse_y() {
+        let mut ppu = ppu_at(0x0000);
+        // fine Y 7, coarse Y 29 -> next vertical nametable
+        ppu.reg_addr.set(0x7000 | (29 << 5));
+        ppu.reg_addr.increment_y();
+        assert_eq!(ppu.reg_addr.get(), 0x0800);
+    }
+
+    #[test]
+    fn test_data_access_outside_rendering_increments_normally() {
+        let mut ppu = ppu_at(0x2345);
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.reg_addr.get(), 0x2346);
+
+        // rendering enabled, but in vblank
+        let mut ppu = ppu_at(0x2345);
+        ppu.write_to_ppu_mask(0b0001_1000);
+        ppu.write_to_ctrl(0b0000_0100);
+        ppu.scan_lines = 241;
+        ppu.write_to_data(0x66);
+        assert_eq!(ppu.reg_addr.get(), 0x2365);
+    }
+
+    #[test]
+    fn test_backdrop_follows_v_during_forced_blanking() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.palette_table[0x00] = 0x0f;
+        ppu.palette_table[0x04] = 0x21;
+
+        assert_eq!(ppu.backdrop_color(), 0x0f);
+
+        // $3F14 mirrors $3F04
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x14);
+        assert_eq!(ppu.backdrop_color(), 0x21);
+
+        ppu.write_to_ppu_mask(0b0000_1000);
+        assert_eq!(ppu.backdrop_color(), 0x0f);
+    }
+
+    #[test]
+    fn test_forced_blanking_frame_uses_backdrop_color() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.palette_table[0x00] = 0x0f;
+        ppu.palette_table[0x04] = 0x21;
+        let tick_frame = |ppu: &mut PPU| {
+            for _ in 0..MAX_CYCLE * ppu.region.scanlines_per_frame() {
+                ppu.tick(1);
+            }
+        };
+
+        // v at $3F14, a mirror of $3F04
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x14);
+        tick_frame(&mut ppu);
+        assert!(ppu.frame().iter().all(|c| *c == 0x21));
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        tick_frame(&mut ppu);
+        assert!(ppu.frame().iter().all(|c| *c == 0x0f));
+    }
+
+    #[test]
+    fn test_palette_mirrors() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x30); // mirrors $3F10, which mirrors $3F00
+        ppu.write_to_data(0x2c);
+        assert_eq!(ppu.palette_table[0x00], 0x2c);
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x25);
+        ppu.write_to_data(0x16);
+        assert_eq!(ppu.palette_table[0x05], 0x16);
+    }
+
+    // ticks one dot at a time for a whole frame; returns (vblank onset line, last line seen)
+    fn run_frame(ppu: &mut PPU) -> (usize, usize) {
+        ppu.write_to_ctrl(0b1000_0000);
+        let (mut vblank_line, mut last_line) = (None, 0);
+        loop {
+            ppu.tick(1);
+            if ppu.scan_lines == 0 && last_line > 0 {
+                break;
+            }
+            last_line = last_line.max(ppu.scan_lines);
+            if vblank_line.is_none() && ppu.reg_status.is_in_vblank() {
+                vblank_line = Some(ppu.scan_lines);
+            }
+        }
+        (vblank_line.unwrap(), last_line)
+    }
+
+    #[test]
+    fn test_region_frame_structure() {
+        let mut ntsc = PPU::new_empty_rom();
+        assert_eq!(run_frame(&mut ntsc), (241, 261));
+
+        let mut dendy = PPU::new_empty_rom();
+        dendy.set_region(Region::Dendy);
+        assert_eq!(run_frame(&mut dendy), (291, 311));
+
+        let mut pal = PPU::new_empty_rom();
+        pal.set_region(Region::Pal);
+        assert_eq!(run_frame(&mut pal), (241, 311));
+    }
+
     #[test]
     fn test_oam_dma() {
         let mut ppu = PPU::new_empty_rom();
@@ -8,6 +8,7 @@ pub trait PPURegister {
 
 // Scroll Register 0x2005
 
+#[derive(Clone)]
 pub struct ScrollRegister{
     pub x: u8,
     pub y: u8,
@@ -37,6 +38,7 @@ impl ScrollRegister {
 }
 
 // Address Register 0x2006
+#[derive(Clone)]
 pub struct AddrRegister{
     pub val: (u8, u8),  // val.0 for high; val.1 for low
     pub hi_ptr: bool,
@@ -79,6 +81,38 @@ impl AddrRegister{
         self.check_mirror();
     }
 
+    // The increments the PPU applies to v while rendering, v being laid out as yyy NN YYYYY XXXXX
+    // (fine Y, nametable, coarse Y, coarse X)
+    pub fn increment_coarse_x(&mut self){
+        let v = self.get();
+        if v & 0x001f == 31 {
+            self.set((v & !0x001f) ^ 0x0400); // wrap into the horizontal nametable
+        } else {
+            self.set(v + 1);
+        }
+    }
+
+    pub fn increment_y(&mut self){
+        let mut v = self.get();
+        if v & 0x7000 != 0x7000 {
+            v += 0x1000; // fine Y
+        } else {
+            v &= !0x7000;
+            let mut coarse_y = (v & 0x03e0) >> 5;
+            if coarse_y == 29 {
+                coarse_y = 0;
+                v ^= 0x0800; // wrap into the vertical nametable
+            } else if coarse_y == 31 {
+                coarse_y = 0; // out of bounds row, wraps without switching nametables
+            } else {
+                coarse_y += 1;
+            }
+            v = (v & !0x03e0) | (coarse_y << 5);
+        }
+        self.set(v);
+        self.check_mirror();
+    }
+
     pub fn check_mirror(&mut self){
         if self.get() > 0x3fff { //mirror down addr above 0x3fff
             self.set(self.get() & 0x3fff);
@@ -145,6 +179,14 @@ bitflags! {
 
 
 
+     pub fn sprite_size(&self) -> u8 {
+        if !self.contains(ControlRegister::SPRITE_SIZE) {
+            8
+        } else {
+            16
+        }
+     }
+
      pub fn vram_addr_increment(&self) -> u8{
         if !self.contains(ControlRegister::VRAM_ADD_INCREMENT) {
             1
@@ -0,0 +1,201 @@
+use crate::bus::Bus;
+
+// Iterative memory search over CPU RAM and PRG-RAM, the classic cheat-finder workflow:
+// take a snapshot, let the game run, take another snapshot and narrow the
+// candidate addresses with one of the filters below. Repeat until one is left.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub addr: u16,
+    pub previous: u8,
+    pub current: u8,
+}
+
+const PRG_RAM_START: u16 = 0x6000;
+
+// Snapshots hold the 2 KiB of CPU RAM followed by PRG-RAM; candidates are indices into them
+pub struct RamSearch {
+    candidates: Vec<usize>,
+    previous: Vec<u8>,
+    current: Vec<u8>,
+    cpu_ram_len: usize,
+}
+
+fn take_snapshot(bus: &Bus) -> Vec<u8> {
+    let mut ram = bus.cpu_ram().to_vec();
+    ram.extend_from_slice(bus.prg_ram());
+    ram
+}
+
+impl RamSearch {
+    // starts a full search: every RAM and PRG-RAM address is a candidate
+    pub fn new(bus: &Bus) -> Self {
+        let ram = take_snapshot(bus);
+        RamSearch {
+            candidates: (0..ram.len()).collect(),
+            previous: ram.clone(),
+            current: ram,
+            cpu_ram_len: bus.cpu_ram().len(),
+        }
+    }
+
+    // call at a frame boundary (or whenever the user asks) before applying a filter
+    pub fn snapshot(&mut self, bus: &Bus) {
+        self.previous = std::mem::replace(&mut self.current, take_snapshot(bus));
+    }
+
+    // CPU address of a snapshot index, PRG-RAM starting at $6000
+    fn address(&self, index: usize) -> u16 {
+        if index < self.cpu_ram_len {
+            index as u16
+        } else {
+            PRG_RAM_START + (index - self.cpu_ram_len) as u16
+        }
+    }
+
+    pub fn equal_to(&mut self, value: u8) {
+        self.retain(|_, current| current == value);
+    }
+
+    pub fn changed(&mut self) {
+        self.retain(|previous, current| previous != current);
+    }
+
+    pub fn unchanged(&mut self) {
+        self.retain(|previous, current| previous == current);
+    }
+
+    pub fn increased(&mut self) {
+        self.retain(|previous, current| current > previous);
+    }
+
+    pub fn decreased(&mut self) {
+        self.retain(|previous, current| current < previous);
+    }
+
+    // n is the signed difference current - previous, with 8-bit wraparound
+    pub fn changed_by(&mut self, n: i16) {
+        self.retain(|previous, current| current.wrapping_sub(previous) == n as u8);
+    }
+
+    pub fn candidates(&self) -> Vec<Candidate> {
+        self.candidates
+            .iter()
+            .map(|&index| Candidate {
+                addr: self.address(index),
+                previous: self.previous[index],
+                current: self.current[index],
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    fn retain<F>(&mut self, filter: F)
+    where
+        F: Fn(u8, u8) -> bool,
+    {
+        let previous = &self.previous;
+        let current = &self.current;
+        self.candidates
+            .retain(|&index| filter(previous[index], current[index]));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+    use crate::cpu::{StopReason, CPU};
+
+    fn run_frame(cpu: &mut CPU) {
+        cpu.set_program_counter(0x0600);
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+    }
+
+    #[test]
+    fn test_decreased_narrows_to_the_lives_counter() {
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        // DEC $40 (lives); INC $41 (frame counter); BRK
+        cpu.load(vec![0xc6, 0x40, 0xe6, 0x41, 0x00]).unwrap();
+        cpu.mem_write(0x40, 5);
+
+        let mut search = RamSearch::new(&cpu.bus);
+        assert_eq!(search.len(), 0x800 + 0x2000); // CPU RAM and PRG-RAM
+
+        run_frame(&mut cpu);
+        search.snapshot(&cpu.bus);
+        search.decreased();
+
+        run_frame(&mut cpu);
+        search.snapshot(&cpu.bus);
+        search.decreased();
+
+        assert_eq!(
+            search.candidates(),
+            vec![Candidate {
+                addr: 0x40,
+                previous: 4,
+                current: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        cpu.mem_write(0x10, 7);
+        cpu.mem_write(0x11, 7);
+        cpu.mem_write(0x12, 7);
+
+        let mut search = RamSearch::new(&cpu.bus);
+        search.equal_to(7);
+        assert_eq!(search.len(), 3);
+
+        cpu.mem_write(0x11, 9);
+        cpu.mem_write(0x12, 5);
+        search.snapshot(&cpu.bus);
+        search.changed();
+        assert_eq!(search.len(), 2);
+
+        search.changed_by(-2);
+        assert_eq!(search.candidates()[0].addr, 0x12);
+        assert_eq!(search.len(), 1);
+
+        search.snapshot(&cpu.bus);
+        search.unchanged();
+        assert_eq!(search.len(), 1);
+        search.increased();
+        assert!(search.is_empty());
+    }
+
+    #[test]
+    fn test_prg_ram_candidate() {
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        // INC $6123 (battery-backed save counter); BRK
+        cpu.load(vec![0xee, 0x23, 0x61, 0x00]).unwrap();
+        cpu.mem_write(0x6123, 1);
+
+        let mut search = RamSearch::new(&cpu.bus);
+        search.equal_to(1);
+        run_frame(&mut cpu);
+        search.snapshot(&cpu.bus);
+        search.changed_by(1);
+
+        assert_eq!(
+            search.candidates(),
+            vec![Candidate {
+                addr: 0x6123,
+                previous: 1,
+                current: 2
+            }]
+        );
+    }
+}
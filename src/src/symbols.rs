@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+// Labels for addresses, from an assembler's or debugger's symbol file, for the disassembler
+// and the human-readable trace. One label per address; a later one replaces an earlier one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    labels: HashMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    // FCEUX name list, `$C123#reset_handler#comment` per line. The `/size` FCEUX puts after
+    // the address of an array is accepted and ignored, as are blank lines.
+    pub fn from_nl(text: &str) -> Result<Self, String> {
+        let mut table = SymbolTable::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fail = |reason: &str| format!("line {}: {}: {}", n + 1, reason, line);
+            let mut fields = line.splitn(3, '#');
+            let addr = fields.next().unwrap();
+            let name = fields.next().ok_or_else(|| fail("expected $ADDR#label#"))?;
+            if !addr.starts_with('$') {
+                return Err(fail("address must start with $"));
+            }
+            let addr = addr.split('/').next().unwrap();
+            let addr = parse_addr(addr).ok_or_else(|| fail("bad address"))?;
+            table.insert_checked(addr, name).map_err(fail)?;
+        }
+        Ok(table)
+    }
+
+    // `C123=reset_handler`, the $ optional. Blank lines and lines starting with ; are skipped.
+    pub fn from_text(text: &str) -> Result<Self, String> {
+        let mut table = SymbolTable::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let fail = |reason: &str| format!("line {}: {}: {}", n + 1, reason, line);
+            let mut fields = line.splitn(2, '=');
+            let addr = fields.next().unwrap().trim();
+            let name = fields.next().ok_or_else(|| fail("expected addr=label"))?.trim();
+            let addr = parse_addr(addr).ok_or_else(|| fail("bad address"))?;
+            table.insert_checked(addr, name).map_err(fail)?;
+        }
+        Ok(table)
+    }
+
+    fn insert_checked(&mut self, addr: u16, name: &str) -> Result<(), &'static str> {
+        if name.is_empty() {
+            return Err("empty label");
+        }
+        if name.contains(char::is_whitespace) {
+            return Err("label contains whitespace");
+        }
+        self.insert(addr, name);
+        Ok(())
+    }
+
+    pub fn insert(&mut self, addr: u16, name: impl Into<String>) {
+        self.labels.insert(addr, name.into());
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    // An operand as format_operand writes it with its address swapped for the label, which
+    // moves to a comment: `init_ppu ; $C4A0`, `(ptr),Y ; $10`. None when nothing is labelled;
+    // immediates are values, never addresses.
+    pub fn annotate(&self, operand: &str) -> Option<String> {
+        if operand.starts_with('#') {
+            return None;
+        }
+        let start = operand.find('$')?;
+        let digits = operand[start + 1..].chars().take_while(char::is_ascii_hexdigit).count();
+        let end = start + 1 + digits;
+        let addr = u16::from_str_radix(&operand[start + 1..end], 16).ok()?;
+        let label = self.label(addr)?;
+        Some(format!(
+            "{}{}{} ; {}",
+            &operand[..start],
+            label,
+            &operand[end..],
+            &operand[start..end]
+        ))
+    }
+}
+
+fn parse_addr(text: &str) -> Option<u16> {
+    let hex = text.strip_prefix('$').unwrap_or(text);
+    if hex.is_empty() || hex.len() > 4 {
+        return None;
+    }
+    u16::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_nl() {
+        let table = SymbolTable::from_nl(
+            "$C000#reset_handler#power on and reset\n\
+             \n\
+             $0200/100#oam_buf#\n\
+             $00F0#ptr#\n",
+        )
+        .unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.label(0xc000), Some("reset_handler"));
+        assert_eq!(table.label(0x0200), Some("oam_buf"));
+        assert_eq!(table.label(0x00f0), Some("ptr"));
+        assert_eq!(table.label(0x0201), None);
+
+        let bad = [
+            ("$C000#reset\n$C003\n", "line 2: expected $ADDR#label#: $C003"),
+            ("C000#reset#", "line 1: address must start with $: C000#reset#"),
+            ("$C0G0#reset#", "line 1: bad address: $C0G0#reset#"),
+            ("$10000#big#", "line 1: bad address: $10000#big#"),
+            ("$C000##", "line 1: empty label: $C000##"),
+            ("$C000#two words#", "line 1: label contains whitespace: $C000#two words#"),
+        ];
+        for (text, err) in bad.iter() {
+            assert_eq!(SymbolTable::from_nl(text), Err(err.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_from_text() {
+        let text = "; zero page\nF0=ptr\n$C4A0 = init_ppu\n\nc4a0=init_ppu2\n";
+        let table = SymbolTable::from_text(text).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.label(0x00f0), Some("ptr"));
+        // the later line wins
+        assert_eq!(table.label(0xc4a0), Some("init_ppu2"));
+
+        let bad = [
+            ("C4A0 init_ppu", "line 1: expected addr=label: C4A0 init_ppu"),
+            ("F0=ptr\nzz=oops", "line 2: bad address: zz=oops"),
+            ("=nothing", "line 1: bad address: =nothing"),
+            ("C4A0=", "line 1: empty label: C4A0="),
+        ];
+        for (text, err) in bad.iter() {
+            assert_eq!(SymbolTable::from_text(text), Err(err.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_annotate() {
+        let mut table = SymbolTable::new();
+        table.insert(0xc4a0, "init_ppu");
+        table.insert(0x0010, "ptr");
+        assert_eq!(table.annotate("$C4A0"), Some("init_ppu ; $C4A0".to_string()));
+        assert_eq!(table.annotate("($10),Y"), Some("(ptr),Y ; $10".to_string()));
+        assert_eq!(table.annotate("$0010,X"), Some("ptr,X ; $0010".to_string()));
+        assert_eq!(table.annotate("#$10"), None);
+        assert_eq!(table.annotate("$C4A1"), None);
+        assert_eq!(table.annotate("A"), None);
+    }
+}
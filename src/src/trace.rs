@@ -1,142 +1,260 @@
 use crate::cpu::AddressingMode;
-use crate::cpu::Mem;
+use crate::cpu::CpuBus;
 use crate::cpu::CPU;
-use crate::opcodes;
-use std::collections::HashMap;
+use crate::opcodes::OpCode;
+use crate::symbols::SymbolTable;
+use std::io::Write;
+use std::sync::Arc;
 
-pub fn trace(cpu: &mut CPU) -> String {
-    let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    // nestest.log lines, see trace()
+    #[default]
+    Nestest,
+    // see trace_human(), labelled when the CPU has trace symbols
+    Human,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceConfig {
+    pub format: TraceFormat,
+    pub every_n: usize, // one line for every `every_n` instructions, 0 counts as 1
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        TraceConfig {
+            format: TraceFormat::Nestest,
+            every_n: 1,
+        }
+    }
+}
+
+// Where the CPU sends trace lines, see CPU::set_trace_writer. A clone starts without a writer,
+// two machines writing into one sink would only interleave.
+#[derive(Default)]
+pub(crate) struct Tracer {
+    writer: Option<Box<dyn Write + Send>>,
+    config: TraceConfig,
+    skipped: usize,
+    symbols: Option<Arc<SymbolTable>>,
+}
+
+impl Clone for Tracer {
+    fn clone(&self) -> Self {
+        Tracer {
+            writer: None,
+            config: self.config,
+            skipped: 0,
+            symbols: self.symbols.clone(),
+        }
+    }
+}
+
+impl Tracer {
+    pub fn set_writer(&mut self, writer: Option<Box<dyn Write + Send>>) {
+        self.writer = writer;
+        self.skipped = 0;
+    }
+
+    pub fn set_config(&mut self, config: TraceConfig) {
+        self.config = config;
+        self.skipped = 0;
+    }
+
+    pub fn set_symbols(&mut self, symbols: Option<Arc<SymbolTable>>) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> Option<&SymbolTable> {
+        self.symbols.as_deref()
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.config.format
+    }
+
+    // counts an instruction, true when it is one to write out
+    #[inline]
+    pub fn due(&mut self) -> bool {
+        if self.writer.is_none() {
+            return false;
+        }
+        if self.skipped + 1 < self.config.every_n {
+            self.skipped += 1;
+            return false;
+        }
+        self.skipped = 0;
+        true
+    }
 
-    let code = cpu.mem_read(cpu.program_counter);
-    let ops = opscodes.get(&code).unwrap();
+    pub fn write_line(&mut self, line: &str) {
+        if let Some(writer) = self.writer.as_mut() {
+            // a closed pipe or a full disk ends the trace, not the emulation
+            if writeln!(writer, "{}", line).is_err() {
+                self.writer = None;
+            }
+        }
+    }
+}
 
-    let begin = cpu.program_counter;
-    let mut hex_dump = vec![];
-    hex_dump.push(code);
+// Bytes the bus can't peek, the APU and PPU registers, show as FF like they do in the log
+fn peek<M: CpuBus>(cpu: &CPU<M>, addr: u16) -> u8 {
+    cpu.bus.peek(addr).unwrap_or(0xff)
+}
 
+// One line of nestest.log for the instruction at PC, without touching the machine
+pub fn trace<M: CpuBus>(cpu: &CPU<M>) -> String {
+    let begin = cpu.program_counter();
+    let code = peek(cpu, begin);
+    let asm_str = match cpu.opcode_table()[code as usize] {
+        Some(ops) => disassemble(cpu, ops, begin),
+        None => format!("{:04x}  {:02x}        .byte ${:02x}", begin, code, code),
+    };
+
+    let (ppu_cycle, ppu_scan_line) = cpu.bus.ppu_info();
+
+    format!(
+        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:3},{:3} CYC:{}",
+        asm_str,
+        cpu.register_a(),
+        cpu.register_x(),
+        cpu.register_y(),
+        cpu.status(),
+        cpu.stack_pointer(),
+        ppu_scan_line,
+        ppu_cycle,
+        cpu.cycles()
+    )
+    .to_ascii_uppercase()
+}
+
+// The instruction at PC in assembler syntax, then the registers: shorter than a nestest line
+// and without the resolved addresses, for reading rather than diffing
+pub fn trace_human<M: CpuBus>(cpu: &CPU<M>) -> String {
+    trace_human_with_symbols(cpu, None)
+}
+
+// trace_human with labelled operands; nestest lines never get labels, they are for diffing
+pub fn trace_human_with_symbols<M: CpuBus>(cpu: &CPU<M>, symbols: Option<&SymbolTable>) -> String {
+    let begin = cpu.program_counter();
+    let code = peek(cpu, begin);
+    let (mnemonic, operand) = match cpu.opcode_table()[code as usize] {
+        Some(ops) => {
+            let operand: Vec<u8> =
+                (1..ops.bytes as u16).map(|i| peek(cpu, begin.wrapping_add(i))).collect();
+            let operand = ops.format_operand(&operand, begin);
+            let annotated = symbols.and_then(|symbols| symbols.annotate(&operand));
+            (ops.mnemonic, annotated.unwrap_or(operand))
+        }
+        None => (".byte", format!("${:02X}", code)),
+    };
+    format!("{:04X}  {: >5} {:14} {}", begin, mnemonic, operand, cpu)
+}
+
+// the address, bytes and disassembly columns of a nestest line
+fn disassemble<M: CpuBus>(cpu: &CPU<M>, ops: &OpCode, begin: u16) -> String {
+    let operand: Vec<u8> =
+        (1..ops.bytes as u16).map(|i| peek(cpu, begin.wrapping_add(i))).collect();
+    let mut hex_dump = vec![ops.code];
+    hex_dump.extend(&operand);
+
+    // a jump's target is shown by the operand itself, nothing is read there
+    let jump = ops.mnemonic == "JMP";
     let (mem_addr, stored_value) = match ops.mode {
-        AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
+        AddressingMode::Immediate | AddressingMode::Relative | AddressingMode::NoneAddressing => {
+            (0, 0)
+        }
+        AddressingMode::Absolute if jump => (0, 0),
         _ => {
-            let (addr, _) = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
+            let addr = cpu
+                .peek_operand_address(&ops.mode, begin.wrapping_add(1))
+                .unwrap_or(0);
+            (addr, peek(cpu, addr))
         }
     };
 
-    let tmp = match ops.len {
-        1 => match ops.code {
-            0x0a | 0x4a | 0x2a | 0x6a => format!("A "),
-            _ => String::from(""),
-        },
-        2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
-            // let value = cpu.mem_read(address));
-            hex_dump.push(address);
-
-            match ops.mode {
-                AddressingMode::Immediate => format!("#${:02x}", address),
-                AddressingMode::ZeroPage => format!("${:02x} = {:02x}", mem_addr, stored_value),
-                AddressingMode::ZeroPage_X => format!(
-                    "${:02x},X @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::ZeroPage_Y => format!(
-                    "${:02x},Y @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Indirect_X => format!(
-                    "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
-                    address,
-                    (address.wrapping_add(cpu.register_x)),
-                    mem_addr,
-                    stored_value
-                ),
-                AddressingMode::Indirect_Y => format!(
-                    "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-                    address,
-                    (mem_addr.wrapping_sub(cpu.register_y as u16)),
-                    mem_addr,
-                    stored_value
-                ),
-                AddressingMode::NoneAddressing => {
-                    // assuming local jumps: BNE, BVS, etc....
-                    let address: usize =
-                        (begin as usize + 2).wrapping_add((address as i8) as usize);
-                    format!("${:04x}", address)
-                }
-
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 2. code {:02x}",
-                    ops.mode, ops.code
-                ),
-            }
+    // the operand as the disassembler shows it, followed by what it resolves to right now
+    let effective = match ops.mode {
+        AddressingMode::Immediate => String::new(),
+        AddressingMode::Absolute if jump => String::new(),
+        AddressingMode::ZeroPage | AddressingMode::Absolute => format!(" = {:02x}", stored_value),
+        AddressingMode::ZeroPage_X | AddressingMode::ZeroPage_Y => {
+            format!(" @ {:02x} = {:02x}", mem_addr, stored_value)
         }
-        3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
-            hex_dump.push(address_lo);
-            hex_dump.push(address_hi);
-
-            let address = cpu.mem_read_u16(begin + 1);
-
-            match ops.mode {
-                AddressingMode::NoneAddressing => {
-                    if ops.code == 0x6c {
-                        //jmp indirect
-                        let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
-                            (hi as u16) << 8 | (lo as u16)
-                        } else {
-                            cpu.mem_read_u16(address)
-                        };
-
-                        // let jmp_addr = cpu.mem_read_u16(address);
-                        format!("(${:04x}) = {:04x}", address, jmp_addr)
-                    } else {
-                        format!("${:04x}", address)
-                    }
-                }
-                AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, stored_value),
-                AddressingMode::Absolute_X => format!(
-                    "${:04x},X @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Absolute_Y => format!(
-                    "${:04x},Y @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
-                    ops.mode, ops.code
-                ),
-            }
+        AddressingMode::Absolute_X | AddressingMode::Absolute_Y => {
+            format!(" @ {:04x} = {:02x}", mem_addr, stored_value)
         }
-        _ => String::from(""),
+        AddressingMode::Indirect_X => format!(
+            " @ {:02x} = {:04x} = {:02x}",
+            operand[0].wrapping_add(cpu.register_x()),
+            mem_addr,
+            stored_value
+        ),
+        AddressingMode::Indirect_Y => format!(
+            " = {:04x} @ {:04x} = {:02x}",
+            mem_addr.wrapping_sub(cpu.register_y() as u16),
+            mem_addr,
+            stored_value
+        ),
+        AddressingMode::ZeroPage_Indirect => format!(" = {:04x} = {:02x}", mem_addr, stored_value),
+        AddressingMode::Indirect => format!(" = {:04x}", mem_addr),
+        AddressingMode::Relative | AddressingMode::NoneAddressing => String::new(),
     };
+    let tmp = ops.format_operand(&operand, begin) + &effective;
 
     let hex_str = hex_dump
         .iter()
         .map(|z| format!("{:02x}", z))
         .collect::<Vec<String>>()
         .join(" ");
-    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
+    // unofficial mnemonics carry a '*', which takes the place of the space before them
+    format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
         .trim()
-        .to_string();
-
-    let (ppu_cycle, ppu_scan_line) = cpu.get_ppu_info();
-
-    format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU Cycles: {} PPU Scan Lines: {}",
-        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,ppu_cycle, ppu_scan_line
-    )
-    .to_ascii_uppercase()
+        .to_string()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::bus::Bus;
+    use crate::cpu::Mem;
     use crate::cartridge::test::test_rom;
+    use crate::cartridge::Rom;
+    use crate::cpu::StopReason;
+    use std::fs;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    // a sink the test can still read after handing it to the CPU
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn lines(&self) -> Vec<String> {
+            let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            text.lines().map(String::from).collect()
+        }
+    }
+
+    // LDX #$05; loop: DEX; BNE loop; BRK -- 12 instructions, the BRK included
+    fn countdown() -> CPU {
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        cpu.load([0xa2, 0x05, 0xca, 0xd0, 0xfd, 0x00]).unwrap();
+        cpu.set_program_counter(0x0600);
+        cpu
+    }
 
     #[test]
     fn test_format_trace() {
@@ -148,24 +266,24 @@ mod test {
         bus.mem_write(104, 0x00);
 
         let mut cpu = CPU::new(bus);
-        cpu.program_counter = 0x64;
-        cpu.register_a = 1;
-        cpu.register_x = 2;
-        cpu.register_y = 3;
+        cpu.set_program_counter(0x64);
+        cpu.set_register_a(1);
+        cpu.set_register_x(2);
+        cpu.set_register_y(3);
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(|cpu| {
             result.push(trace(cpu));
-        });
+        }).into_result().unwrap();
         assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0,  0 CYC:0",
             result[0]
         );
         assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0,  6 CYC:2",
             result[1]
         );
         assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0, 12 CYC:4",
             result[2]
         );
     }
@@ -185,15 +303,195 @@ mod test {
         bus.mem_write(0x400, 0xAA);
 
         let mut cpu = CPU::new(bus);
-        cpu.program_counter = 0x64;
-        cpu.register_y = 0;
+        cpu.set_program_counter(0x64);
+        cpu.set_register_y(0);
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(|cpu| {
             result.push(trace(cpu));
-        });
+        }).into_result().unwrap();
         assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
             result[0]
         );
     }
+
+    fn nestest() -> CPU {
+        let rom = Rom::new(&fs::read("dump/nestest.nes").unwrap()).unwrap();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.power_on();
+        cpu.set_program_counter(0xc000);
+        cpu
+    }
+
+    #[test]
+    fn test_matches_nestest_log() {
+        let expected = [
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+            "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10",
+            "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12",
+            "C5F9  86 10     STX $10 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15",
+            "C5FB  86 11     STX $11 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 54 CYC:18",
+            "C5FD  20 2D C7  JSR $C72D                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 63 CYC:21",
+            "C72D  EA        NOP                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 81 CYC:27",
+            "C72E  38        SEC                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 87 CYC:29",
+            "C72F  B0 04     BCS $C735                       A:00 X:00 Y:00 P:27 SP:FB PPU:  0, 93 CYC:31",
+        ];
+        let mut cpu = nestest();
+        for line in expected.iter() {
+            assert_eq!(trace(&cpu), *line);
+            cpu.step().unwrap();
+        }
+    }
+
+    // a log line, A X Y P SP on it, and the RAM its annotation reads
+    type LogState = (&'static str, [u8; 5], &'static [(u16, u8)]);
+
+    // Later lines of the log, with the CPU and RAM put in the state the log shows. Only the
+    // part before the PPU position is compared.
+    #[test]
+    fn test_matches_nestest_log_annotations() {
+        let lines: [LogState; 9] = [
+            (
+                "C6BD  04 A9    *NOP $A9 = 00                    A:AA X:97 Y:4E P:EF SP:F9",
+                [0xaa, 0x97, 0x4e, 0xef, 0xf9],
+                &[(0x00a9, 0x00)],
+            ),
+            (
+                "C6C9  0C A9 A9 *NOP $A9A9 = A9                  A:AA X:97 Y:4E P:EF SP:F7",
+                [0xaa, 0x97, 0x4e, 0xef, 0xf7],
+                &[],
+            ),
+            (
+                "DBB5  6C FF 02  JMP ($02FF) = 0300              A:60 X:07 Y:00 P:65 SP:F9",
+                [0x60, 0x07, 0x00, 0x65, 0xf9],
+                &[(0x02ff, 0x00), (0x0200, 0x03)],
+            ),
+            (
+                "D940  B1 97     LDA ($97),Y = FFFF @ 0033 = A3  A:FF X:65 Y:34 P:65 SP:FB",
+                [0xff, 0x65, 0x34, 0x65, 0xfb],
+                &[(0x0097, 0xff), (0x0098, 0xff), (0x0033, 0xa3)],
+            ),
+            (
+                "CFE3  A1 80     LDA ($80,X) @ 82 = 0300 = 5B    A:5A X:02 Y:69 P:25 SP:FB",
+                [0x5a, 0x02, 0x69, 0x25, 0xfb],
+                &[(0x0082, 0x00), (0x0083, 0x03), (0x0300, 0x5b)],
+            ),
+            (
+                "E1E4  BC FF 05  LDY $05FF,X @ 0689 = BB         A:66 X:8A Y:00 P:26 SP:FB",
+                [0x66, 0x8a, 0x00, 0x26, 0xfb],
+                &[(0x0689, 0xbb)],
+            ),
+            (
+                "DBEF  B4 FF     LDY $FF,X @ 89 = BB             A:66 X:8A Y:00 P:26 SP:FB",
+                [0x66, 0x8a, 0x00, 0x26, 0xfb],
+                &[(0x0089, 0xbb)],
+            ),
+            (
+                "CEFC  4A        LSR A                           A:01 X:55 Y:69 P:65 SP:FB",
+                [0x01, 0x55, 0x69, 0x65, 0xfb],
+                &[],
+            ),
+            (
+                "C68B  8D 15 40  STA $4015 = FF                  A:02 X:FF Y:15 P:25 SP:FB",
+                [0x02, 0xff, 0x15, 0x25, 0xfb],
+                &[],
+            ),
+        ];
+        for (line, [a, x, y, p, sp], ram) in lines.iter() {
+            let mut cpu = nestest();
+            cpu.set_program_counter(u16::from_str_radix(&line[..4], 16).unwrap());
+            cpu.set_register_a(*a);
+            cpu.set_register_x(*x);
+            cpu.set_register_y(*y);
+            cpu.set_status(*p);
+            cpu.set_stack_pointer(*sp);
+            for (addr, value) in ram.iter() {
+                cpu.bus.mem_write(*addr, *value);
+            }
+            let traced = trace(&cpu);
+            assert_eq!(traced.split(" PPU:").next().unwrap(), *line);
+        }
+    }
+
+    #[test]
+    fn test_trace_writer() {
+        let mut cpu = countdown();
+        let sink = SharedBuf::default();
+        cpu.set_trace_writer(Some(Box::new(sink.clone())));
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+        let lines = sink.lines();
+        assert_eq!(lines.len(), 12);
+        assert!(lines[0].starts_with("0600  A2 05     LDX #$05"));
+        assert!(lines[11].starts_with("0605  00        BRK"));
+
+        let mut cpu = countdown();
+        let sink = SharedBuf::default();
+        cpu.set_trace_writer(Some(Box::new(sink.clone())));
+        cpu.set_trace_config(TraceConfig {
+            format: TraceFormat::Human,
+            every_n: 4,
+        });
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+        assert_eq!(
+            sink.lines(),
+            vec![
+                "0602    DEX                A:00 X:04 Y:00 P:24 SP:FD PC:0602",
+                "0602    DEX                A:00 X:02 Y:00 P:24 SP:FD PC:0602",
+                "0605    BRK                A:00 X:00 Y:00 P:26 SP:FD PC:0605",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_human_trace_with_symbols() {
+        let mut cpu = countdown();
+        let sink = SharedBuf::default();
+        cpu.set_trace_writer(Some(Box::new(sink.clone())));
+        cpu.set_trace_config(TraceConfig {
+            format: TraceFormat::Human,
+            every_n: 1,
+        });
+        let symbols = SymbolTable::from_text("0602=loop").unwrap();
+        cpu.set_trace_symbols(Some(Arc::new(symbols)));
+        assert_eq!(cpu.run().stop, StopReason::Brk);
+        assert_eq!(
+            sink.lines()[2],
+            "0603    BNE loop ; $0602   A:00 X:04 Y:00 P:24 SP:FD PC:0603"
+        );
+        // nestest lines stay comparable with the log
+        assert!(trace(&cpu).starts_with("0606  00        BRK"));
+    }
+
+    #[test]
+    fn test_clone_does_not_share_the_trace_writer() {
+        let mut cpu = countdown();
+        let sink = SharedBuf::default();
+        cpu.set_trace_writer(Some(Box::new(sink.clone())));
+        let mut fork = cpu.clone();
+        assert_eq!(fork.run().stop, StopReason::Brk);
+        assert!(sink.lines().is_empty());
+    }
+
+    // Tracing off costs a branch per instruction, on it formats a line for each one
+    #[test]
+    #[ignore]
+    fn bench_trace_overhead() {
+        let time = |traced: bool| {
+            let mut cpu = CPU::new(Bus::new(test_rom()));
+            // LDX #$00; LDY #$00; loop: DEX; BNE loop; DEY; BNE loop; BRK
+            cpu.load([0xa2, 0x00, 0xa0, 0x00, 0xca, 0xd0, 0xfd, 0x88, 0xd0, 0xfa, 0x00]).unwrap();
+            cpu.set_program_counter(0x0600);
+            if traced {
+                cpu.set_trace_writer(Some(Box::new(io::sink())));
+            }
+            let start = std::time::Instant::now();
+            let summary = cpu.run();
+            assert_eq!(summary.stop, StopReason::Brk);
+            (summary.instructions, start.elapsed())
+        };
+        let (instructions, off) = time(false);
+        let (_, on) = time(true);
+        println!("{} instructions: {:?} untraced, {:?} traced", instructions, off, on);
+        assert!(off < on);
+    }
 }